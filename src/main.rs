@@ -6,16 +6,31 @@ mod listview;
 
 use eframe::egui;
 use crate::test::run_test;
+use crate::participant::Participant;
+use crate::secret_santa::solver::{solver_named, solvers};
 use crate::ui::SecretSanta;
 
 fn main() -> eframe::Result<()> {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
+
+    // `--solver <name>` picks the solver the GUI starts with
+    let solver = std::env::args().skip_while(|arg| arg != "--solver").nth(1);
+    if let Some(name) = &solver {
+        if solver_named::<Participant>(name).is_none() {
+            let names = solvers::<Participant>().iter().map(|solver| solver.name()).collect::<Vec<_>>().join(", ");
+            eprintln!("Unknown solver {}, expected one of: {}", name, names);
+        }
+    }
+
     let options = eframe::NativeOptions {
         ..Default::default()
     };
     eframe::run_native(
         "Secret Santa",
         options,
-        Box::new(|cc| Ok(Box::new(SecretSanta::default())))
+        Box::new(|cc| Ok(Box::new(match solver {
+            Some(name) => SecretSanta::default().solver(&name),
+            None => SecretSanta::default(),
+        })))
     )
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter};
 
-#[derive(Eq, PartialEq, Hash, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone)]
 pub struct Participant {
     pub name: String
 }
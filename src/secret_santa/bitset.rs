@@ -0,0 +1,118 @@
+/// A set of participant indices from `0` to `capacity - 1`, stored as one bit per index.
+///
+/// A row of an `n` by `n` matrix of pairs takes `n / 8` bytes instead of `n`, and scans skip 64 absent
+/// indices at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    /// An empty set of indices below `capacity`.
+    pub fn new(capacity: usize) -> Self {
+        Self { words: vec![0; capacity.div_ceil(64)] }
+    }
+
+    /// The set of every index below `capacity`.
+    pub fn full(capacity: usize) -> Self {
+        let mut set = Self { words: vec![u64::MAX; capacity.div_ceil(64)] };
+        if !capacity.is_multiple_of(64) {
+            if let Some(last) = set.words.last_mut() {
+                *last = (1 << (capacity % 64)) - 1;
+            }
+        }
+        set
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// How many indices are in the set.
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    /// Adds every index of `other`, which must have the same capacity.
+    pub fn union_with(&mut self, other: &BitSet) {
+        for (word, &other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Lists the indices in the set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        ones(self.words.iter().copied())
+    }
+
+    /// Lists the indices in both this set and `other`, which must have the same capacity, in increasing order.
+    pub fn intersection<'s>(&'s self, other: &'s BitSet) -> impl Iterator<Item = usize> + 's {
+        ones(self.words.iter().zip(&other.words).map(|(&word, &other)| word & other))
+    }
+}
+
+/// Lists the positions of the bits set in `words`, 64 positions per word.
+fn ones(words: impl Iterator<Item = u64>) -> impl Iterator<Item = usize> {
+    words.enumerate().flat_map(|(index, word)| {
+        let mut rest = word;
+        std::iter::from_fn(move || {
+            if rest == 0 {
+                return None;
+            }
+            let bit = rest.trailing_zeros() as usize;
+            rest &= rest - 1;
+            Some(index * 64 + bit)
+        })
+    })
+}
+
+/// The allowed pairs of `allowed`, where `allowed[giver]` lists who `giver` may give to, as one set
+/// of recipients per giver.
+pub fn adjacency(allowed: &[Vec<usize>]) -> Vec<BitSet> {
+    allowed
+        .iter()
+        .map(|recipients| {
+            let mut row = BitSet::new(allowed.len());
+            for &recipient in recipients {
+                row.insert(recipient);
+            }
+            row
+        })
+        .collect()
+}
+
+/// The columns of the square matrix `rows`: who may give to each recipient, given what each giver may give to.
+pub fn transpose(rows: &[BitSet]) -> Vec<BitSet> {
+    let mut columns = vec![BitSet::new(rows.len()); rows.len()];
+    for (row, recipients) in rows.iter().enumerate() {
+        for column in recipients.iter() {
+            columns[column].insert(row);
+        }
+    }
+    columns
+}
+
+/// `rows` with every recipient `r` renamed `names[r]`, where `names` is a permutation.
+pub fn rename(rows: &[BitSet], names: &[usize]) -> Vec<BitSet> {
+    rows.iter()
+        .map(|recipients| {
+            let mut renamed = BitSet::new(names.len());
+            for recipient in recipients.iter() {
+                renamed.insert(names[recipient]);
+            }
+            renamed
+        })
+        .collect()
+}
@@ -0,0 +1,129 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use crate::secret_santa::group::Group;
+use crate::secret_santa::history::{History, HistoryPolicy};
+
+/// How gifts may flow between participants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrawMode {
+    /// Everyone is part of one big gift cycle.
+    #[default]
+    SingleCycle,
+    /// Anyone may give to anyone they aren't excluded from, so gifts may form several smaller cycles.
+    Derangement,
+}
+
+/// The rules a Secret Santa draw has to follow.
+pub struct Constraints<'a, T> {
+    pub(crate) exclusions: HashMap<&'a T, HashSet<&'a T>>,
+    pub(crate) mode: DrawMode,
+    pub(crate) min_cycle_length: usize,
+    pub(crate) history: Option<(History<'a, T>, HistoryPolicy)>,
+    pub(crate) pair_costs: HashMap<(&'a T, &'a T), f64>,
+    pub(crate) required: Vec<(&'a T, &'a T)>,
+}
+
+impl<T> Default for Constraints<'_, T> {
+    fn default() -> Self {
+        Self {
+            exclusions: HashMap::new(),
+            mode: DrawMode::default(),
+            min_cycle_length: 2,
+            history: None,
+            pair_costs: HashMap::new(),
+            required: Vec::new(),
+        }
+    }
+}
+
+impl<'a, T: Eq + Hash> Constraints<'a, T> {
+    /// Adds every exclusion of `exclusions`, where each giver maps to the participants they may not give to.
+    pub fn exclusions(mut self, exclusions: HashMap<&'a T, HashSet<&'a T>>) -> Self {
+        for (giver, excluded) in exclusions {
+            self.exclusions.entry(giver).or_default().extend(excluded);
+        }
+        self
+    }
+
+    /// Forbids `giver` from giving to `recipient`.
+    pub fn exclude(mut self, giver: &'a T, recipient: &'a T) -> Self {
+        self.exclusions.entry(giver).or_default().insert(recipient);
+        self
+    }
+
+    /// Requires `giver` to give to `recipient`.
+    ///
+    /// Draws fail if this conflicts with other requirements, exclusions or cycle rules.
+    pub fn require(mut self, giver: &'a T, recipient: &'a T) -> Self {
+        self.required.push((giver, recipient));
+        self
+    }
+
+    /// Forbids the members of `group` from giving to each other.
+    pub fn group(mut self, group: &'a Group<T>) -> Self {
+        for (giver, recipient) in group.exclusions() {
+            self = self.exclude(giver, recipient);
+        }
+        self
+    }
+
+    /// Forbids the members of each group from giving to each other, see [`Constraints::group`].
+    pub fn groups(mut self, groups: impl IntoIterator<Item = &'a Group<T>>) -> Self {
+        for group in groups {
+            self = self.group(group);
+        }
+        self
+    }
+
+    pub fn mode(mut self, mode: DrawMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the fewest participants a gift cycle may have when gifts may form several cycles.
+    ///
+    /// A minimum of `3` rules out reciprocal pairs, where two participants give to each other.
+    pub fn min_cycle_length(mut self, length: usize) -> Self {
+        self.min_cycle_length = length;
+        self
+    }
+
+    /// Takes previous years' draws into account, so that participants don't keep giving to the same people.
+    pub fn history(mut self, history: History<'a, T>, policy: HistoryPolicy) -> Self {
+        self.history = Some((history, policy));
+        self
+    }
+
+    /// Adds a cost to `giver` giving to `recipient`, so the draw avoids it when possible.
+    ///
+    /// A draw minimizes the total cost of its pairs, picking at random among the cheapest ones.
+    /// A negative cost makes a pair preferred instead. Costs never override exclusions.
+    pub fn pair_cost(mut self, giver: &'a T, recipient: &'a T, cost: f64) -> Self {
+        *self.pair_costs.entry((giver, recipient)).or_default() += cost;
+        self
+    }
+
+    /// Adds every cost of `costs`, keyed by giver and recipient. See [`Constraints::pair_cost`].
+    pub fn pair_costs(mut self, costs: HashMap<(&'a T, &'a T), f64>) -> Self {
+        for ((giver, recipient), cost) in costs {
+            self = self.pair_cost(giver, recipient, cost);
+        }
+        self
+    }
+
+    /// Total cost of an assignment, from pair costs and from penalized history.
+    pub fn cost_of(&self, assignment: &HashMap<&'a T, &'a T>) -> f64 {
+        let mut cost: f64 = assignment
+            .iter()
+            .map(|(&giver, &recipient)| self.pair_costs.get(&(giver, recipient)).copied().unwrap_or(0.0))
+            .sum();
+        if let Some((history, HistoryPolicy::Penalize { decay })) = &self.history {
+            cost += history
+                .pairs()
+                .filter(|(_, giver, recipient)| assignment.get(giver) == Some(recipient))
+                .map(|(age, _, _)| decay.powi(age as i32 - 1))
+                .sum::<f64>();
+        }
+        cost
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::secret_santa::trace::TraceEvent;
+
+/// Lets anyone holding a clone stop a running draw, e.g. from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a search has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Partial assignments tried so far.
+    pub nodes: u64,
+    /// Times the search gave up on a partial assignment and went back.
+    pub backtracks: u64,
+    /// How complete the current partial assignment is, out of `max_depth`.
+    pub depth: usize,
+    pub max_depth: usize,
+}
+
+/// What searches went through, gathered by their [`SearchControl`] to explain slow or failed draws.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchStats {
+    /// Partial assignments tried, over every search.
+    pub nodes: u64,
+    pub backtracks: u64,
+    /// The most complete partial assignment reached.
+    pub max_depth: usize,
+    /// Time spent in each phase of the draw, in the order they first ran.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+/// Why a search stopped before it was done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    Cancelled,
+    TimedOut,
+    OutOfNodes,
+}
+
+impl Display for Interruption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interruption::Cancelled => write!(f, "the draw was cancelled"),
+            Interruption::TimedOut => write!(f, "the draw ran out of time"),
+            Interruption::OutOfNodes => write!(f, "the draw tried too many partial assignments"),
+        }
+    }
+}
+
+/// What a search split from a [`SearchControl`] went through, to add back to that control once the search is over.
+#[derive(Debug, Clone, Default)]
+pub struct SplitRecord {
+    stats: SearchStats,
+    events: Vec<TraceEvent>,
+    truncated: bool,
+}
+
+/// Nodes between two looks at the clock, the cancel token and the progress callback.
+const CHECK_INTERVAL: u32 = 1024;
+
+/// Limits on how long a search may run, and a way to follow and stop it.
+///
+/// Searches call [`SearchControl::check`] at every node. The default control never stops a search.
+#[derive(Default)]
+pub struct SearchControl<'c> {
+    deadline: Option<Instant>,
+    max_nodes: Option<u64>,
+    cancel: Vec<CancelToken>,
+    progress: Option<Box<dyn FnMut(Progress) + Send + 'c>>,
+    /// Nodes since the last look at the clock, the cancel token and the progress callback.
+    unchecked: u32,
+    stats: SearchStats,
+    /// The progress last checked, to add up the nodes of searches that run one after the other.
+    last: Progress,
+    trace_limit: usize,
+    events: Vec<TraceEvent>,
+    truncated: bool,
+}
+
+impl<'c> SearchControl<'c> {
+    /// Stops the search once `limit` has passed from now.
+    pub fn time_limit(mut self, limit: Duration) -> Self {
+        self.deadline = Some(Instant::now() + limit);
+        self
+    }
+
+    /// Stops the search once it has tried more than `nodes` partial assignments.
+    pub fn node_limit(mut self, nodes: u64) -> Self {
+        self.max_nodes = Some(nodes);
+        self
+    }
+
+    /// Stops the search once `token` is cancelled, or any other token given before.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel.push(token);
+        self
+    }
+
+    /// Calls `report` with the progress of the search every now and then.
+    pub fn on_progress(mut self, report: impl FnMut(Progress) + Send + 'c) -> Self {
+        self.progress = Some(Box::new(report));
+        self
+    }
+
+    /// Records up to `limit` steps of the search, see [`SearchControl::events`].
+    pub fn record_trace(mut self, limit: usize) -> Self {
+        self.trace_limit = limit;
+        self
+    }
+
+    /// A control with the same deadline, cancel tokens and trace limit but no node limit nor progress reporting,
+    /// for one of several searches running side by side.
+    pub fn split<'d>(&self) -> SearchControl<'d> {
+        SearchControl {
+            deadline: self.deadline,
+            cancel: self.cancel.clone(),
+            trace_limit: self.trace_limit,
+            ..SearchControl::default()
+        }
+    }
+
+    /// What this control went through, for the control it was split from.
+    pub fn split_record(&self) -> SplitRecord {
+        SplitRecord { stats: self.stats.clone(), events: self.events.clone(), truncated: self.truncated }
+    }
+
+    /// Reports `progress` to the progress callback, if any, without checking whether to stop.
+    pub fn report(&mut self, progress: Progress) {
+        if let Some(report) = &mut self.progress {
+            report(progress);
+        }
+    }
+
+    /// What the searches went through so far.
+    pub fn stats(&self) -> &SearchStats {
+        &self.stats
+    }
+
+    /// The steps recorded so far, and whether later ones were dropped. Nothing is recorded unless
+    /// [`SearchControl::record_trace`] was called.
+    pub fn events(&self) -> (&[TraceEvent], bool) {
+        (&self.events, self.truncated)
+    }
+
+    /// Records a step of the search, if a trace is being recorded.
+    pub fn record(&mut self, event: TraceEvent) {
+        if self.events.len() < self.trace_limit {
+            self.events.push(event);
+        } else if self.trace_limit > 0 {
+            self.truncated = true;
+        }
+    }
+
+    /// Runs `phase`, adding the time it takes to the phase called `name`.
+    pub fn time_phase<R>(&mut self, name: &'static str, phase: impl FnOnce(&mut Self) -> R) -> R {
+        let start = Instant::now();
+        let result = phase(self);
+        self.add_phase_time(name, start.elapsed());
+        result
+    }
+
+    fn add_phase_time(&mut self, name: &'static str, elapsed: Duration) {
+        match self.stats.phases.iter_mut().find(|(phase, _)| *phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => self.stats.phases.push((name, elapsed)),
+        }
+    }
+
+    /// Adds the nodes, backtracks and depth of a search split from this control to what this control has seen.
+    pub fn add_split_stats(&mut self, record: &SplitRecord) {
+        self.stats.nodes += record.stats.nodes;
+        self.stats.backtracks += record.stats.backtracks;
+        self.stats.max_depth = self.stats.max_depth.max(record.stats.max_depth);
+    }
+
+    /// Takes over the phase times and recorded steps of the split search whose result is kept, after
+    /// [`SearchControl::add_split_stats`].
+    pub fn adopt_split(&mut self, record: SplitRecord) {
+        for (name, elapsed) in record.stats.phases {
+            self.add_phase_time(name, elapsed);
+        }
+        for event in record.events {
+            self.record(event);
+        }
+        self.truncated |= record.truncated;
+    }
+
+    /// Nodes the searches may still try before the node limit, if there is one.
+    pub fn nodes_left(&self) -> Option<u64> {
+        self.max_nodes.map(|max_nodes| max_nodes.saturating_sub(self.stats.nodes))
+    }
+
+    /// Returns why the search should stop if it was cancelled or ran out of time, without counting a node.
+    pub fn check_stopped(&self) -> Result<(), Interruption> {
+        if self.cancel.iter().any(CancelToken::is_cancelled) {
+            return Err(Interruption::Cancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Interruption::TimedOut);
+        }
+        Ok(())
+    }
+
+    /// Records that the search got to `progress`, returning why it should stop if it should.
+    pub fn check(&mut self, progress: Progress) -> Result<(), Interruption> {
+        // Counts going down mean another search started from scratch
+        let since = |now: u64, last: u64| if now >= last { now - last } else { now };
+        self.stats.nodes += since(progress.nodes, self.last.nodes);
+        self.stats.backtracks += since(progress.backtracks, self.last.backtracks);
+        self.stats.max_depth = self.stats.max_depth.max(progress.depth);
+        self.last = progress;
+
+        if self.max_nodes.is_some_and(|max_nodes| progress.nodes > max_nodes) {
+            return Err(Interruption::OutOfNodes);
+        }
+        // Look from the very first node, so that a search can't start once it should stop
+        let due = self.unchecked == 0;
+        self.unchecked = (self.unchecked + 1) % CHECK_INTERVAL;
+        if !due {
+            return Ok(());
+        }
+
+        self.report(progress);
+        self.check_stopped()
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::{Display, Formatter};
+use crate::secret_santa::bitset::BitSet;
+
+/// How many valid assignments a draw has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AssignmentCount {
+    /// Counted exactly.
+    Exact(u128),
+    /// Estimated from random walks down the search tree, as a base 10 logarithm since counts get huge.
+    /// Negative infinity means no walk found a valid assignment.
+    Estimated { log10: f64 },
+}
+
+impl Display for AssignmentCount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AssignmentCount::Exact(count) => write!(f, "{}", count),
+            AssignmentCount::Estimated { log10 } if log10 == f64::NEG_INFINITY => write!(f, "probably none"),
+            AssignmentCount::Estimated { log10 } if log10 < 15.0 => write!(f, "about {:.0}", 10f64.powf(log10)),
+            AssignmentCount::Estimated { log10 } => write!(f, "about 10^{:.1}", log10),
+        }
+    }
+}
+
+/// Counts the perfect matchings of givers to recipients, where `allowed[giver]` is the set of recipients that
+/// `giver` may give to.
+///
+/// That is the permanent of the matrix of allowed pairs, computed with Ryser's formula in `O(2^n n)` time,
+/// visiting subsets of recipients in Gray code order so that each one only changes one column.
+pub fn count_matchings(allowed: &[BitSet]) -> u128 {
+    let count = allowed.len();
+    if count == 0 {
+        return 1;
+    }
+
+    // perm(A) = (-1)^n * sum over recipient subsets S of (-1)^|S| * prod over givers of |allowed[giver] ∩ S|
+    let mut row_sums = vec![0i128; count];
+    let mut total: i128 = 0;
+    for step in 1..1usize << count {
+        // The subset is the bits of `step ^ (step >> 1)`, which changes bit `changed` at each step
+        let changed = step.trailing_zeros() as usize;
+        let subset = step ^ (step >> 1);
+        let delta = if subset & (1 << changed) != 0 { 1 } else { -1 };
+        for (giver, sum) in row_sums.iter_mut().enumerate() {
+            if allowed[giver].contains(changed) {
+                *sum += delta;
+            }
+        }
+
+        let product: i128 = row_sums.iter().product();
+        total += if subset.count_ones().is_multiple_of(2) { product } else { -product };
+    }
+
+    let total = if count.is_multiple_of(2) { total } else { -total };
+    total as u128
+}
+
+/// Counts the single gift cycles going through every participant, where `allowed[giver]` is the set of recipients
+/// `giver` may give to.
+///
+/// Every cycle is counted once from participant `0`, counting the paths from there through each set of
+/// participants (Held-Karp), in `O(2^n n^2)` time.
+pub fn count_single_cycles(allowed: &[BitSet]) -> u128 {
+    let count = allowed.len();
+    if count < 2 {
+        return 0;
+    }
+
+    // paths[visited][end]: paths from `0` through the participants in `visited`, which leave out `0`, ending at `end`
+    let others = count - 1;
+    let mut paths = vec![vec![0u128; others]; 1 << others];
+    for recipient in allowed[0].iter() {
+        paths[1 << (recipient - 1)][recipient - 1] = 1;
+    }
+    for visited in 1..1usize << others {
+        for end in (0..others).filter(|&end| visited & (1 << end) != 0) {
+            let ways = paths[visited][end];
+            if ways == 0 {
+                continue;
+            }
+            for next in allowed[end + 1].iter().filter(|&next| next != 0) {
+                if visited & (1 << (next - 1)) == 0 {
+                    paths[visited | (1 << (next - 1))][next - 1] += ways;
+                }
+            }
+        }
+    }
+
+    let everyone = (1 << others) - 1;
+    (0..others).filter(|&end| allowed[end + 1].contains(0)).map(|end| paths[everyone][end]).sum()
+}
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use itertools::Itertools;
+use crate::secret_santa::control::Interruption;
+
+/// Explains why a Secret Santa draw could not be made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrawError<'a, T> {
+    /// A draw needs more participants, at least two when everyone gives a single gift.
+    TooFewParticipants { count: usize, needed: usize },
+    /// A draw where everyone gives several gifts was asked for no gift at all.
+    NoGifts,
+    /// The participant is excluded from giving to every other participant.
+    NoAllowedRecipients(&'a T),
+    /// Every other participant is excluded from giving to this participant.
+    NoAllowedGivers(&'a T),
+    /// Together, these givers may only give to fewer recipients than there are givers.
+    HallViolation { givers: Vec<&'a T>, recipients: Vec<&'a T> },
+    /// Someone joining a draw already takes part in it.
+    AlreadyParticipating(&'a T),
+    /// A required pairing involves someone who isn't a participant.
+    UnknownParticipant(&'a T),
+    /// A giver is required to give to a recipient they are excluded from.
+    RequiredPairExcluded { giver: &'a T, recipient: &'a T },
+    /// Two required pairings share a giver or a recipient, who can't give or receive that many gifts.
+    ConflictingRequirements { first: (&'a T, &'a T), second: (&'a T, &'a T) },
+    /// Required pairings close a gift cycle through these participants, shorter than cycle rules allow.
+    RequiredCycleTooShort(Vec<&'a T>),
+    /// Every possibility was explored without finding a valid draw.
+    SearchExhausted,
+    /// The participant can't join the draw by changing one or two existing pairs, though a new draw may work.
+    NoRoomForJoiner(&'a T),
+    /// The search was stopped before it found a valid draw.
+    Interrupted(Interruption),
+    /// No valid draw came up after this many random attempts.
+    SamplingFailed { attempts: usize },
+}
+
+impl<T: Display> Display for DrawError<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrawError::TooFewParticipants { count, needed } => {
+                write!(f, "at least {} participants are needed, but only {} were given", needed, count)
+            }
+            DrawError::NoGifts => write!(f, "every participant has to give at least one gift"),
+            DrawError::NoAllowedRecipients(giver) => {
+                write!(f, "{} is excluded from giving to every other participant", giver)
+            }
+            DrawError::NoAllowedGivers(recipient) => {
+                write!(f, "every other participant is excluded from giving to {}", recipient)
+            }
+            DrawError::HallViolation { givers, recipients } => write!(
+                f,
+                "{} may only give to {}, which is not enough recipients for {} givers",
+                givers.iter().join(", "),
+                if recipients.is_empty() { "nobody".to_string() } else { recipients.iter().join(", ") },
+                givers.len()
+            ),
+            DrawError::AlreadyParticipating(participant) => {
+                write!(f, "{} already takes part in the draw", participant)
+            }
+            DrawError::UnknownParticipant(participant) => {
+                write!(f, "{} is required to give or receive a gift, but is not a participant", participant)
+            }
+            DrawError::RequiredPairExcluded { giver, recipient } => {
+                write!(f, "{} is required to give to {}, but is excluded from it", giver, recipient)
+            }
+            DrawError::ConflictingRequirements { first: (first_giver, first_recipient), second: (second_giver, second_recipient) } => write!(
+                f,
+                "{} is required to give to {}, which conflicts with {} being required to give to {}",
+                first_giver, first_recipient, second_giver, second_recipient
+            ),
+            DrawError::RequiredCycleTooShort(cycle) => {
+                write!(f, "required pairings close a gift cycle that is too short: {}", cycle.iter().join(" -> "))
+            }
+            DrawError::SearchExhausted => {
+                write!(f, "no assignment satisfies every rule")
+            }
+            DrawError::NoRoomForJoiner(joiner) => {
+                write!(f, "{} can't join without changing more than two pairs, the draw has to be made again", joiner)
+            }
+            DrawError::Interrupted(interruption) => write!(f, "{}", interruption),
+            DrawError::SamplingFailed { attempts } => {
+                write!(f, "no valid assignment came up in {} random attempts", attempts)
+            }
+        }
+    }
+}
+
+impl<T: Display + Debug> Error for DrawError<'_, T> {}
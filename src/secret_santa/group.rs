@@ -0,0 +1,25 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+
+/// Participants that may not give to each other, like a household or a team.
+///
+/// "No one gives within their own household" and "everyone gives outside their team" are both declared
+/// with one group per household or team, instead of an exclusion for every pair of members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group<T: Eq + Hash> {
+    pub name: String,
+    pub members: HashSet<T>,
+}
+
+impl<T: Eq + Hash> Group<T> {
+    pub fn new(name: String, members: impl IntoIterator<Item = T>) -> Self {
+        Self { name, members: members.into_iter().collect() }
+    }
+
+    /// Lists every pair of distinct members, as the exclusions this group stands for.
+    pub fn exclusions(&self) -> impl Iterator<Item = (&T, &T)> {
+        self.members
+            .iter()
+            .flat_map(move |giver| self.members.iter().filter(move |&recipient| recipient != giver).map(move |recipient| (giver, recipient)))
+    }
+}
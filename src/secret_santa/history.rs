@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How previous years' draws are taken into account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryPolicy {
+    /// Forbids giving to anyone given to during the last `years` years.
+    Exclude { years: usize },
+    /// Avoids giving to the same person again, preferring repeats from longer ago.
+    ///
+    /// Repeating last year's pair costs `1`, and each year before costs `decay` times the year after it.
+    /// The draw minimizes the total cost.
+    Penalize { decay: f64 },
+}
+
+/// Previous draws, from the oldest to the most recent year, each mapping every giver to their recipient.
+pub struct History<'a, T> {
+    years: Vec<HashMap<&'a T, &'a T>>,
+}
+
+impl<T> Default for History<'_, T> {
+    fn default() -> Self {
+        Self { years: Vec::new() }
+    }
+}
+
+impl<'a, T: Eq + Hash> History<'a, T> {
+    /// Adds a year's draw, more recent than every year already added.
+    pub fn year(mut self, assignment: HashMap<&'a T, &'a T>) -> Self {
+        self.years.push(assignment);
+        self
+    }
+
+    /// Iterates over the previous years' pairs, along with how many years ago they were drawn, starting at `1`.
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, &'a T, &'a T)> + '_ {
+        self.years
+            .iter()
+            .rev()
+            .enumerate()
+            .flat_map(|(age, year)| year.iter().map(move |(&giver, &recipient)| (age + 1, giver, recipient)))
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+use crate::secret_santa::bitset::{adjacency, transpose, BitSet};
+
+/// A matching between givers and recipients, both indexed from `0` to `n - 1`.
+pub struct Matching {
+    /// The recipient matched to each giver.
+    pub recipient_of: Vec<Option<usize>>,
+    /// The giver matched to each recipient.
+    pub giver_of: Vec<Option<usize>>,
+}
+
+/// Finds a maximum matching of the bipartite giver/recipient graph with the Hopcroft-Karp algorithm.
+///
+/// `allowed[giver]` is the set of recipients that `giver` may give to, tried in increasing order.
+pub fn maximum_matching(allowed: &[BitSet]) -> Matching {
+    let count = allowed.len();
+    let mut matching = Matching {
+        recipient_of: vec![None; count],
+        giver_of: vec![None; count],
+    };
+    let mut layer = vec![usize::MAX; count];
+
+    while build_layers(allowed, &matching, &mut layer) {
+        for giver in 0..count {
+            if matching.recipient_of[giver].is_none() {
+                augment(allowed, &mut matching, &mut layer, giver);
+            }
+        }
+    }
+
+    matching
+}
+
+/// Breadth-first pass of Hopcroft-Karp, layering givers by alternating path length from the free givers.
+///
+/// Returns whether an augmenting path exists.
+fn build_layers(allowed: &[BitSet], matching: &Matching, layer: &mut [usize]) -> bool {
+    let mut queue = VecDeque::new();
+    for (giver, depth) in layer.iter_mut().enumerate() {
+        if matching.recipient_of[giver].is_none() {
+            *depth = 0;
+            queue.push_back(giver);
+        } else {
+            *depth = usize::MAX;
+        }
+    }
+
+    let mut found = false;
+    while let Some(giver) = queue.pop_front() {
+        for recipient in allowed[giver].iter() {
+            match matching.giver_of[recipient] {
+                None => found = true,
+                Some(next) if layer[next] == usize::MAX => {
+                    layer[next] = layer[giver] + 1;
+                    queue.push_back(next);
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    found
+}
+
+/// Depth-first pass of Hopcroft-Karp, following the layers to augment along a shortest path from `giver`.
+fn augment(allowed: &[BitSet], matching: &mut Matching, layer: &mut [usize], giver: usize) -> bool {
+    for recipient in allowed[giver].iter() {
+        let free = match matching.giver_of[recipient] {
+            None => true,
+            Some(next) => layer[next] == layer[giver] + 1 && augment(allowed, matching, layer, next),
+        };
+        if free {
+            matching.recipient_of[giver] = Some(recipient);
+            matching.giver_of[recipient] = Some(giver);
+            return true;
+        }
+    }
+
+    // Dead end, don't visit this giver again during this phase
+    layer[giver] = usize::MAX;
+    false
+}
+
+/// Finds a set of givers that, together, may give to fewer recipients than there are givers.
+///
+/// Returns the givers and the recipients they may give to, or `None` if the matching is perfect.
+/// By Hall's theorem, such a set exists exactly when `matching` is a maximum matching that is not perfect.
+pub fn hall_violator(allowed: &[BitSet], matching: &Matching) -> Option<(Vec<usize>, Vec<usize>)> {
+    let start = matching.recipient_of.iter().position(Option::is_none)?;
+
+    let mut reached_givers = vec![false; allowed.len()];
+    let mut reached_recipients = vec![false; allowed.len()];
+    let mut queue = VecDeque::from([start]);
+    reached_givers[start] = true;
+
+    // Every recipient reachable through an alternating path is matched, otherwise the matching would not be maximum
+    while let Some(giver) = queue.pop_front() {
+        for recipient in allowed[giver].iter() {
+            if reached_recipients[recipient] {
+                continue;
+            }
+            reached_recipients[recipient] = true;
+            if let Some(next) = matching.giver_of[recipient] {
+                if !reached_givers[next] {
+                    reached_givers[next] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    let givers = (0..allowed.len()).filter(|&giver| reached_givers[giver]).collect();
+    let recipients = (0..allowed.len()).filter(|&recipient| reached_recipients[recipient]).collect();
+    Some((givers, recipients))
+}
+
+/// Chooses `gifts` distinct recipients for every giver, so that every recipient also receives `gifts` gifts,
+/// where `allowed[giver]` is the set of recipients `giver` may give to.
+///
+/// Every `(giver, recipient)` pair of `required` is part of the result, and must be allowed.
+///
+/// Returns the recipients of each giver, or `None` if that's impossible. This is a maximum flow from givers
+/// to recipients, grown one augmenting path at a time: a giver that can't be augmented never can be later.
+pub fn regular_assignment(allowed: &[BitSet], gifts: usize, required: &[(usize, usize)]) -> Option<Vec<Vec<usize>>> {
+    let count = allowed.len();
+    let givers = transpose(allowed);
+
+    let mut gives = vec![BitSet::new(count); count];
+    let mut fixed = vec![BitSet::new(count); count];
+    let mut received = vec![0; count];
+    let mut given = vec![0; count];
+    for &(giver, recipient) in required {
+        gives[giver].insert(recipient);
+        fixed[giver].insert(recipient);
+        received[recipient] += 1;
+        given[giver] += 1;
+    }
+
+    for source in 0..count {
+        for _ in given[source]..gifts {
+            // Breadth-first search for a path giving `source` one more recipient, moving gifts along the way
+            let mut recipient_reached_from: Vec<Option<usize>> = vec![None; count];
+            let mut giver_reached_from: Vec<Option<usize>> = vec![None; count];
+            let mut queue = VecDeque::from([source]);
+            let mut reached_givers = vec![false; count];
+            reached_givers[source] = true;
+
+            let mut free_recipient = None;
+            'search: while let Some(giver) = queue.pop_front() {
+                for recipient in allowed[giver].iter() {
+                    if gives[giver].contains(recipient) || recipient_reached_from[recipient].is_some() {
+                        continue;
+                    }
+                    recipient_reached_from[recipient] = Some(giver);
+                    if received[recipient] < gifts {
+                        free_recipient = Some(recipient);
+                        break 'search;
+                    }
+                    // The recipient is full, one of their givers has to give to someone else instead
+                    for other in givers[recipient].iter() {
+                        if gives[other].contains(recipient) && !fixed[other].contains(recipient) && !reached_givers[other] {
+                            reached_givers[other] = true;
+                            giver_reached_from[other] = Some(recipient);
+                            queue.push_back(other);
+                        }
+                    }
+                }
+            }
+
+            let mut recipient = free_recipient?;
+            received[recipient] += 1;
+            loop {
+                let giver = recipient_reached_from[recipient]?;
+                gives[giver].insert(recipient);
+                match giver_reached_from[giver] {
+                    Some(previous) => {
+                        gives[giver].remove(previous);
+                        recipient = previous;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    Some(gives.iter().map(|recipients| recipients.iter().collect()).collect())
+}
+
+/// Splits the gifts of [`regular_assignment`], where every giver gives to and every recipient receives from
+/// `recipients_of[0].len()` others, into that many perfect matchings, given as the recipient of each giver.
+///
+/// Every regular bipartite graph has a perfect matching, and taking one out leaves a regular graph again,
+/// so this never fails.
+pub fn perfect_matchings(recipients_of: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let gifts = recipients_of.first().map_or(0, Vec::len);
+    let mut remaining = adjacency(recipients_of);
+    (0..gifts)
+        .map(|_| {
+            let matching = maximum_matching(&remaining);
+            let recipient_of: Vec<usize> = matching
+                .recipient_of
+                .into_iter()
+                .map(|recipient| recipient.expect("regular graphs have a perfect matching"))
+                .collect();
+            for (recipients, &recipient) in remaining.iter_mut().zip(&recipient_of) {
+                recipients.remove(recipient);
+            }
+            recipient_of
+        })
+        .collect()
+}
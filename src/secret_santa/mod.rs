@@ -0,0 +1,17 @@
+pub mod secret_santa;
+pub mod bitset;
+pub mod constraints;
+pub mod control;
+pub mod counting;
+pub mod draw_error;
+pub mod group;
+pub mod history;
+pub mod matching;
+pub mod optimize;
+pub mod relaxation;
+pub mod sampling;
+pub mod sat;
+pub mod search;
+pub mod solver;
+pub mod trace;
+pub mod violation;
\ No newline at end of file
@@ -0,0 +1,156 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use crate::secret_santa::bitset::BitSet;
+
+/// The cost of each giver giving to each recipient, both indexed from `0` to `n - 1`. Pairs not listed cost nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Costs {
+    pairs: HashMap<(usize, usize), f64>,
+}
+
+impl Costs {
+    pub fn add(&mut self, giver: usize, recipient: usize, cost: f64) {
+        *self.pairs.entry((giver, recipient)).or_insert(0.0) += cost;
+    }
+
+    pub fn get(&self, giver: usize, recipient: usize) -> f64 {
+        self.pairs.get(&(giver, recipient)).copied().unwrap_or(0.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// The same costs with every recipient `r` renamed `names[r]`.
+    pub fn rename_recipients(&self, names: &[usize]) -> Costs {
+        Costs { pairs: self.pairs.iter().map(|(&(giver, recipient), &cost)| ((giver, names[recipient]), cost)).collect() }
+    }
+
+    /// Total cost of an assignment, given as the recipient of each giver.
+    pub fn total(&self, recipient_of: &[usize]) -> f64 {
+        recipient_of.iter().enumerate().map(|(giver, &recipient)| self.get(giver, recipient)).sum()
+    }
+}
+
+/// A giver reached by Dijkstra's algorithm, ordered so that `BinaryHeap` pops the closest one first.
+struct Reached {
+    distance: f64,
+    giver: usize,
+}
+
+impl PartialEq for Reached {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Reached {}
+
+impl PartialOrd for Reached {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Reached {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+/// Finds a perfect matching of givers to recipients with the lowest total cost, where `allowed[giver]` is the
+/// set of recipients that `giver` may give to.
+///
+/// Returns the recipient of each giver, or `None` if no perfect matching exists. Uses the Hungarian method,
+/// growing the matching one giver at a time along shortest augmenting paths found with Dijkstra's algorithm.
+pub fn min_cost_matching(allowed: &[BitSet], costs: &Costs) -> Option<Vec<usize>> {
+    let count = allowed.len();
+    if allowed.iter().any(BitSet::is_empty) {
+        return None;
+    }
+
+    // Potentials keep every reduced cost `cost - giver_potential - recipient_potential` non-negative,
+    // and the reduced cost of every matched pair at zero
+    let mut giver_potential: Vec<f64> = allowed
+        .iter()
+        .enumerate()
+        .map(|(giver, recipients)| recipients.iter().map(|r| costs.get(giver, r)).fold(f64::INFINITY, f64::min))
+        .collect();
+    let mut recipient_potential = vec![0.0; count];
+    let mut giver_of: Vec<Option<usize>> = vec![None; count];
+    let mut recipient_of: Vec<Option<usize>> = vec![None; count];
+
+    for source in 0..count {
+        let mut giver_distance = vec![f64::INFINITY; count];
+        let mut recipient_distance = vec![f64::INFINITY; count];
+        let mut reached_from: Vec<Option<usize>> = vec![None; count];
+        let mut done = vec![false; count];
+        let mut heap = BinaryHeap::from([Reached { distance: 0.0, giver: source }]);
+        giver_distance[source] = 0.0;
+
+        // Shortest alternating path from `source` to a free recipient
+        let mut free_recipient = None;
+        while let Some(Reached { distance, giver }) = heap.pop() {
+            if done[giver] || distance > giver_distance[giver] {
+                continue;
+            }
+            done[giver] = true;
+
+            for recipient in allowed[giver].iter() {
+                let reduced = costs.get(giver, recipient) - giver_potential[giver] - recipient_potential[recipient];
+                let candidate = distance + reduced.max(0.0);
+                if candidate < recipient_distance[recipient] {
+                    recipient_distance[recipient] = candidate;
+                    reached_from[recipient] = Some(giver);
+                    match giver_of[recipient] {
+                        Some(next) if candidate < giver_distance[next] => {
+                            giver_distance[next] = candidate;
+                            heap.push(Reached { distance: candidate, giver: next });
+                        }
+                        Some(_) => {}
+                        None => {
+                            if free_recipient.is_none_or(|free| candidate < recipient_distance[free]) {
+                                free_recipient = Some(recipient);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Once the closest free recipient is at least as close as every giver left, its path is shortest
+            let nearest = heap.peek().map_or(f64::INFINITY, |next| next.distance);
+            if free_recipient.is_some_and(|free| recipient_distance[free] <= nearest) {
+                break;
+            }
+        }
+
+        let free_recipient = free_recipient?;
+        let shortest = recipient_distance[free_recipient];
+
+        for giver in 0..count {
+            if done[giver] && giver_distance[giver] < shortest {
+                giver_potential[giver] += shortest - giver_distance[giver];
+            }
+        }
+        for recipient in 0..count {
+            if recipient_distance[recipient] < shortest && giver_of[recipient].is_some_and(|giver| done[giver]) {
+                recipient_potential[recipient] -= shortest - recipient_distance[recipient];
+            }
+        }
+
+        // Flip the matching along the path
+        let mut recipient = free_recipient;
+        loop {
+            let giver = reached_from[recipient]?;
+            let previous = recipient_of[giver];
+            recipient_of[giver] = Some(recipient);
+            giver_of[recipient] = Some(giver);
+            match previous {
+                Some(previous) => recipient = previous,
+                None => break,
+            }
+        }
+    }
+
+    recipient_of.into_iter().collect()
+}
@@ -0,0 +1,96 @@
+use rand::Rng;
+use std::collections::HashSet;
+use crate::secret_santa::bitset::BitSet;
+use crate::secret_santa::control::{Interruption, SearchControl};
+use crate::secret_santa::optimize::{min_cost_matching, Costs};
+use crate::secret_santa::search::find_cycles;
+
+/// Finds the fewest excluded pairs to allow so that gift cycles can go through every participant, each at least
+/// `min_cycle_length` participants long, where `allowed[giver]` is the set of recipients `giver` may give to.
+///
+/// Pairs in `kept` stay excluded. Returns the pairs to allow as `(giver, recipient)`, sorted, which is empty if
+/// nothing needs to be allowed, or `None` if even allowing every other pair isn't enough. Works by looking for the
+/// cheapest assignment where every excluded pair costs one and every allowed pair nothing.
+///
+/// A search stopped by `control` after finding some assignment returns the fewest pairs found so far.
+pub fn minimum_relaxation<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    kept: &HashSet<(usize, usize)>,
+    min_cycle_length: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<(usize, usize)>>, Interruption> {
+    let count = allowed.len();
+    if count < 2 {
+        return Ok(None);
+    }
+
+    let mut costs = Costs::default();
+    let relaxed: Vec<BitSet> = allowed
+        .iter()
+        .enumerate()
+        .map(|(giver, recipients)| {
+            let mut relaxed = BitSet::full(count);
+            relaxed.remove(giver);
+            for recipient in 0..count {
+                if kept.contains(&(giver, recipient)) {
+                    relaxed.remove(recipient);
+                } else if recipient != giver && !recipients.contains(recipient) {
+                    costs.add(giver, recipient, 1.0);
+                }
+            }
+            relaxed
+        })
+        .collect();
+
+    let recipient_of = match min_cycle_length <= 2 {
+        true => min_cost_matching(&relaxed, &costs),
+        false => find_cycles(&relaxed, min_cycle_length, Some(&costs), control, rng)?,
+    };
+
+    Ok(recipient_of.map(|recipient_of| {
+        recipient_of
+            .into_iter()
+            .enumerate()
+            .filter(|&(giver, recipient)| costs.get(giver, recipient) > 0.0)
+            .collect()
+    }))
+}
+
+/// Lists up to `limit` different sets of excluded pairs whose removal makes the draw possible, fewest pairs first.
+///
+/// The first set is one of the smallest possible. Each of the others is one of the smallest keeping one of
+/// the pairs of the first set excluded, so together they offer a choice of which rules to drop.
+/// Pairs in `kept` always stay excluded.
+///
+/// Fails if `control` stops the search for the first set. Stopping it later only leaves fewer alternatives.
+pub fn relaxations<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    kept: &HashSet<(usize, usize)>,
+    min_cycle_length: usize,
+    limit: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<Vec<(usize, usize)>>, Interruption> {
+    let Some(smallest) = minimum_relaxation(allowed, kept, min_cycle_length, control, rng)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut found = vec![smallest.clone()];
+    for &pair in &smallest {
+        if found.len() >= limit {
+            break;
+        }
+        let mut kept = kept.clone();
+        kept.insert(pair);
+        match minimum_relaxation(allowed, &kept, min_cycle_length, control, rng) {
+            Ok(Some(other)) if !found.contains(&other) => found.push(other),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    found.truncate(limit);
+    found.sort_by_key(Vec::len);
+    Ok(found)
+}
@@ -0,0 +1,193 @@
+use itertools::Itertools;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use crate::secret_santa::bitset::BitSet;
+use crate::secret_santa::control::{Interruption, Progress, SearchControl};
+
+/// How to pick an assignment uniformly at random among all valid ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingMethod {
+    /// Draws random assignments until one is valid, giving up after `attempts` tries.
+    ///
+    /// Every valid assignment is exactly as likely, but valid ones get rare as events grow.
+    Rejection { attempts: usize },
+    /// Starts from a valid assignment and rearranges it `steps` times at random, keeping it valid.
+    ///
+    /// The rearrangements are a Markov chain whose stationary distribution is uniform over the valid
+    /// assignments it can reach, so the result gets closer to a uniform pick as `steps` grows.
+    MarkovChain { steps: usize },
+}
+
+impl SamplingMethod {
+    /// Picks a method suited to the number of participants.
+    pub fn for_participants(count: usize) -> Self {
+        if count <= 8 {
+            SamplingMethod::Rejection { attempts: 1_000_000 }
+        } else {
+            SamplingMethod::MarkovChain { steps: 20 * count * (count.ilog2() as usize + 1) }
+        }
+    }
+}
+
+/// The rules an assignment of participants `0..n`, given as the recipient of each giver, has to follow.
+pub struct Rules {
+    pub can_give: Vec<BitSet>,
+    pub single_cycle: bool,
+    pub min_cycle_length: usize,
+}
+
+impl Rules {
+    pub fn new(allowed: &[BitSet], single_cycle: bool, min_cycle_length: usize) -> Self {
+        let can_give = allowed.to_vec();
+        let min_cycle_length = if single_cycle { allowed.len() } else { min_cycle_length.max(2) };
+        Self { can_give, single_cycle, min_cycle_length }
+    }
+
+    pub fn is_valid(&self, recipient_of: &[usize]) -> bool {
+        recipient_of.iter().enumerate().all(|(giver, &recipient)| self.can_give[giver].contains(recipient))
+            && (0..recipient_of.len()).all(|start| cycle_length(recipient_of, start) >= self.min_cycle_length)
+    }
+}
+
+/// Counts the participants in the gift cycle going through `start`.
+fn cycle_length(recipient_of: &[usize], start: usize) -> usize {
+    let mut length = 1;
+    let mut current = recipient_of[start];
+    while current != start {
+        current = recipient_of[current];
+        length += 1;
+    }
+    length
+}
+
+/// Draws uniformly random assignments until one follows `rules`, or `None` after `attempts` tries, unless `control`
+/// stops it first. Every attempt counts as a node.
+///
+/// A single cycle is drawn with Sattolo's algorithm, which picks uniformly among single cycles,
+/// and anything else with a uniformly shuffled permutation.
+pub fn rejection_sample<R: Rng + ?Sized>(
+    rules: &Rules,
+    attempts: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<usize>>, Interruption> {
+    let count = rules.can_give.len();
+    for attempt in 0..attempts {
+        control.check(Progress { nodes: attempt as u64 + 1, backtracks: attempt as u64, depth: 0, max_depth: count })?;
+        let mut recipient_of: Vec<usize> = (0..count).collect();
+        if rules.single_cycle {
+            for i in (1..count).rev() {
+                recipient_of.swap(i, rng.gen_range(0..i));
+            }
+        } else {
+            recipient_of.shuffle(rng);
+        }
+        if rules.is_valid(&recipient_of) {
+            return Ok(Some(recipient_of));
+        }
+    }
+    Ok(None)
+}
+
+/// Rearranges a valid assignment `steps` times at random, only keeping rearrangements that follow `rules`.
+///
+/// Each step proposes either moving one participant elsewhere in the cycles, or swapping the recipients of two
+/// givers (which splits or merges cycles, so it is never used for a single cycle). Both proposals are as likely
+/// as their reverse, so by the Metropolis rule every valid assignment the chain reaches is equally likely.
+/// Every step counts as a node of `control`, which may stop the chain.
+pub fn markov_chain_sample<R: Rng + ?Sized>(
+    rules: &Rules,
+    mut recipient_of: Vec<usize>,
+    steps: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<usize>, Interruption> {
+    let count = recipient_of.len();
+    if count < 3 {
+        return Ok(recipient_of);
+    }
+
+    let mut giver_of = vec![0; count];
+    for (giver, &recipient) in recipient_of.iter().enumerate() {
+        giver_of[recipient] = giver;
+    }
+
+    for step in 0..steps {
+        control.check(Progress { nodes: step as u64 + 1, backtracks: 0, depth: count, max_depth: count })?;
+        if !rules.single_cycle && rng.gen_bool(0.5) {
+            swap_recipients(rules, &mut recipient_of, &mut giver_of, rng);
+        } else {
+            move_participant(rules, &mut recipient_of, &mut giver_of, rng);
+        }
+    }
+
+    Ok(recipient_of)
+}
+
+/// Takes a random participant out of their cycle and puts them after another random participant.
+fn move_participant<R: Rng + ?Sized>(rules: &Rules, recipient_of: &mut [usize], giver_of: &mut [usize], rng: &mut R) {
+    let count = recipient_of.len();
+    let moved = rng.gen_range(0..count);
+    let (giver, recipient) = (giver_of[moved], recipient_of[moved]);
+
+    // Any participant but `moved` and their giver, after whom `moved` already is
+    let mut after = rng.gen_range(0..count - 2);
+    for skipped in [moved, giver].into_iter().sorted_unstable() {
+        if after >= skipped {
+            after += 1;
+        }
+    }
+    let before = recipient_of[after];
+
+    // A participant in a pair can't leave it without their partner giving to themselves
+    if giver == recipient
+        || !rules.can_give[giver].contains(recipient)
+        || !rules.can_give[after].contains(moved)
+        || !rules.can_give[moved].contains(before)
+    {
+        return;
+    }
+    let left_cycle = if rules.min_cycle_length > 2 { cycle_length(recipient_of, moved) } else { 0 };
+
+    recipient_of[giver] = recipient;
+    giver_of[recipient] = giver;
+    recipient_of[after] = moved;
+    giver_of[moved] = after;
+    recipient_of[moved] = before;
+    giver_of[before] = moved;
+
+    // Moving to another cycle shortens the cycle that was left
+    if rules.min_cycle_length > 2 && cycle_length(recipient_of, giver) != left_cycle && left_cycle - 1 < rules.min_cycle_length {
+        recipient_of[after] = before;
+        giver_of[before] = after;
+        recipient_of[giver] = moved;
+        giver_of[moved] = giver;
+        recipient_of[moved] = recipient;
+        giver_of[recipient] = moved;
+    }
+}
+
+/// Swaps the recipients of two random givers.
+fn swap_recipients<R: Rng + ?Sized>(rules: &Rules, recipient_of: &mut [usize], giver_of: &mut [usize], rng: &mut R) {
+    let count = recipient_of.len();
+    let first = rng.gen_range(0..count);
+    let second = (first + rng.gen_range(1..count)) % count;
+    let (first_recipient, second_recipient) = (recipient_of[first], recipient_of[second]);
+    if !rules.can_give[first].contains(second_recipient) || !rules.can_give[second].contains(first_recipient) {
+        return;
+    }
+
+    recipient_of[first] = second_recipient;
+    giver_of[second_recipient] = first;
+    recipient_of[second] = first_recipient;
+    giver_of[first_recipient] = second;
+
+    // Givers that were in the same cycle now are in two, which both have to be long enough
+    let too_short = |start| cycle_length(recipient_of, start) < rules.min_cycle_length;
+    if rules.min_cycle_length > 2 && (too_short(first) || too_short(second)) {
+        recipient_of[first] = first_recipient;
+        giver_of[first_recipient] = first;
+        recipient_of[second] = second_recipient;
+        giver_of[second_recipient] = second;
+    }
+}
@@ -0,0 +1,577 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::Not;
+use crate::secret_santa::control::{Interruption, Progress, SearchControl};
+
+/// A boolean variable or its negation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lit(u32);
+
+impl Lit {
+    pub fn new(var: usize, positive: bool) -> Self {
+        Lit((var as u32) << 1 | !positive as u32)
+    }
+
+    pub fn var(self) -> usize {
+        (self.0 >> 1) as usize
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 & 1 == 0
+    }
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Not for Lit {
+    type Output = Lit;
+
+    fn not(self) -> Lit {
+        Lit(self.0 ^ 1)
+    }
+}
+
+/// A variable waiting to be branched on, ordered so that `BinaryHeap` pops the most active one first.
+struct Candidate {
+    activity: f64,
+    var: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.activity.total_cmp(&other.activity)
+    }
+}
+
+/// The value of `lit` given the value of every variable: `1` if true, `-1` if false and `0` if unassigned.
+fn value(values: &[i8], lit: Lit) -> i8 {
+    let value = values[lit.var()];
+    if lit.is_positive() { value } else { -value }
+}
+
+/// Conflicts before the first restart, later restarts following the Luby sequence.
+const RESTART_BASE: usize = 100;
+/// How quickly the activity of variables missing from recent conflicts fades.
+const ACTIVITY_DECAY: f64 = 0.95;
+
+/// A bound on the total weight of the true literals among some weighted literals.
+struct WeightLimit {
+    /// The literals and their weights, heaviest first.
+    terms: Vec<(Lit, u64)>,
+    bound: u64,
+    /// Total weight of the terms true so far.
+    sum: u128,
+}
+
+/// The `i`th term of the Luby sequence 1, 1, 2, 1, 1, 2, 4, 1, 1, 2, 1, 1, 2, 4, 8, ...
+fn luby(mut i: usize) -> usize {
+    loop {
+        let mut size = 1;
+        while size < i + 1 {
+            size = 2 * size + 1;
+        }
+        if size == i + 1 {
+            return size.div_ceil(2);
+        }
+        i -= size / 2;
+    }
+}
+
+/// A conflict-driven clause learning (CDCL) SAT solver.
+///
+/// Clauses can be added between calls to [`Sat::solve`], which keeps what it learned so far. Propagation
+/// uses two watched literals per clause, branching follows the variables involved in the most recent conflicts,
+/// and the search restarts on a Luby schedule.
+pub struct Sat {
+    clauses: Vec<Vec<Lit>>,
+    /// For each literal, the clauses watching it, which have it as one of their first two literals.
+    watches: Vec<Vec<usize>>,
+    values: Vec<i8>,
+    /// Decision level each variable was assigned at.
+    levels: Vec<usize>,
+    /// Clause that implied each variable, whose first literal is the implied one, or `None` for decisions.
+    reasons: Vec<Option<usize>>,
+    /// Assigned literals in assignment order.
+    trail: Vec<Lit>,
+    /// Where each decision level starts in `trail`.
+    level_starts: Vec<usize>,
+    /// How much of `trail` has been propagated.
+    propagated: usize,
+    activity: Vec<f64>,
+    activity_increment: f64,
+    candidates: BinaryHeap<Candidate>,
+    /// Value each variable last had, tried first when branching on it again.
+    phases: Vec<bool>,
+    /// Whether the clauses are known to be unsatisfiable.
+    unsatisfiable: bool,
+    /// Variables met while analyzing a conflict, all false in between.
+    seen: Vec<bool>,
+    /// Decisions and conflicts over every call to `solve`, reported as nodes and backtracks.
+    decisions: u64,
+    conflicts: u64,
+    limits: Vec<WeightLimit>,
+    /// For each literal, the weight limits it is a term of, with its weight there.
+    weighted: Vec<Vec<(usize, u64)>>,
+}
+
+impl Default for Sat {
+    fn default() -> Self {
+        Self {
+            clauses: Vec::new(),
+            watches: Vec::new(),
+            values: Vec::new(),
+            levels: Vec::new(),
+            reasons: Vec::new(),
+            trail: Vec::new(),
+            level_starts: Vec::new(),
+            propagated: 0,
+            activity: Vec::new(),
+            activity_increment: 1.0,
+            candidates: BinaryHeap::new(),
+            phases: Vec::new(),
+            unsatisfiable: false,
+            seen: Vec::new(),
+            decisions: 0,
+            conflicts: 0,
+            limits: Vec::new(),
+            weighted: Vec::new(),
+        }
+    }
+}
+
+impl Sat {
+    /// Adds a variable, which is first tried as `phase` when branching on it.
+    pub fn new_var(&mut self, phase: bool) -> usize {
+        let var = self.values.len();
+        self.watches.extend([Vec::new(), Vec::new()]);
+        self.weighted.extend([Vec::new(), Vec::new()]);
+        self.values.push(0);
+        self.levels.push(0);
+        self.reasons.push(None);
+        self.activity.push(0.0);
+        self.phases.push(phase);
+        self.seen.push(false);
+        self.candidates.push(Candidate { activity: 0.0, var });
+        var
+    }
+
+    /// Nudges the branching order of `var`, e.g. with small random amounts so that solutions vary.
+    pub fn bump(&mut self, var: usize, amount: f64) {
+        self.activity[var] += amount;
+        self.candidates.push(Candidate { activity: self.activity[var], var });
+    }
+
+    fn decision_level(&self) -> usize {
+        self.level_starts.len()
+    }
+
+    fn assign(&mut self, lit: Lit, reason: Option<usize>) {
+        let var = lit.var();
+        self.values[var] = if lit.is_positive() { 1 } else { -1 };
+        self.levels[var] = self.decision_level();
+        self.reasons[var] = reason;
+        self.trail.push(lit);
+        for &(limit, weight) in &self.weighted[lit.index()] {
+            self.limits[limit].sum += weight as u128;
+        }
+    }
+
+    /// Undoes every assignment made after decision level `level`.
+    fn backtrack(&mut self, level: usize) {
+        if self.decision_level() <= level {
+            return;
+        }
+        let start = self.level_starts[level];
+        for lit in self.trail.drain(start..) {
+            let var = lit.var();
+            for &(limit, weight) in &self.weighted[lit.index()] {
+                self.limits[limit].sum -= weight as u128;
+            }
+            self.values[var] = 0;
+            self.phases[var] = lit.is_positive();
+            self.candidates.push(Candidate { activity: self.activity[var], var });
+        }
+        self.level_starts.truncate(level);
+        self.propagated = start;
+    }
+
+    /// Adds a clause, which is satisfied when at least one of its literals is true.
+    ///
+    /// May be called between calls to [`Sat::solve`]. An empty clause makes the problem unsatisfiable.
+    pub fn add_clause(&mut self, lits: &[Lit]) {
+        self.backtrack(0);
+        if lits.iter().any(|&lit| value(&self.values, lit) == 1) {
+            return;
+        }
+        let mut clause: Vec<Lit> = lits.iter().copied().filter(|&lit| value(&self.values, lit) == 0).collect();
+        clause.sort_unstable_by_key(|lit| lit.0);
+        clause.dedup();
+        // A literal and its negation end up next to each other, and make the clause always true
+        if clause.windows(2).any(|pair| pair[0] == !pair[1]) {
+            return;
+        }
+
+        match clause.len() {
+            0 => self.unsatisfiable = true,
+            1 => {
+                self.assign(clause[0], None);
+                if self.propagate().is_some() {
+                    self.unsatisfiable = true;
+                }
+            }
+            _ => {
+                self.attach(clause);
+            }
+        }
+    }
+
+    /// Requires at most one of `lits` to be true, pairwise for a few literals and with a sequential counter
+    /// (Sinz's encoding) for more.
+    pub fn add_at_most_one(&mut self, lits: &[Lit]) {
+        if lits.len() <= 5 {
+            for (i, &first) in lits.iter().enumerate() {
+                for &second in &lits[i + 1..] {
+                    self.add_clause(&[!first, !second]);
+                }
+            }
+            return;
+        }
+
+        // `seen[i]` is true when one of the first `i + 1` literals is
+        let seen: Vec<Lit> = (0..lits.len() - 1).map(|_| Lit::new(self.new_var(false), true)).collect();
+        self.add_clause(&[!lits[0], seen[0]]);
+        for i in 1..lits.len() - 1 {
+            self.add_clause(&[!lits[i], seen[i]]);
+            self.add_clause(&[!seen[i - 1], seen[i]]);
+            self.add_clause(&[!lits[i], !seen[i - 1]]);
+        }
+        self.add_clause(&[!lits[lits.len() - 1], !seen[lits.len() - 2]]);
+    }
+
+    /// Requires exactly one of `lits` to be true.
+    pub fn add_exactly_one(&mut self, lits: &[Lit]) {
+        self.add_clause(lits);
+        self.add_at_most_one(lits);
+    }
+
+    /// Requires the total weight of the true literals of `terms` to be at most `bound`, and returns the index of
+    /// the limit to tighten it later with [`Sat::tighten_weight_limit`].
+    ///
+    /// The limit is checked as literals become true, rather than encoded in clauses: once a literal would take
+    /// the total over the bound, it is made false, with a clause listing enough of the true literals as the reason.
+    pub fn add_at_most_weight(&mut self, terms: &[(Lit, u64)], bound: u64) -> usize {
+        self.backtrack(0);
+        let index = self.limits.len();
+        let mut terms: Vec<(Lit, u64)> = terms.iter().copied().filter(|&(_, weight)| weight > 0).collect();
+        terms.sort_by_key(|&(_, weight)| std::cmp::Reverse(weight));
+        let sum = terms.iter().filter(|&&(lit, _)| value(&self.values, lit) == 1).map(|&(_, weight)| weight as u128).sum();
+        for &(lit, weight) in &terms {
+            self.weighted[lit.index()].push((index, weight));
+        }
+        self.limits.push(WeightLimit { terms, bound, sum });
+        self.tighten_weight_limit(index, bound);
+        index
+    }
+
+    /// Lowers the bound of a limit added by [`Sat::add_at_most_weight`].
+    ///
+    /// May be called between calls to [`Sat::solve`]. Clauses learned so far stay valid, as the bound only goes down.
+    pub fn tighten_weight_limit(&mut self, limit: usize, bound: u64) {
+        self.backtrack(0);
+        self.limits[limit].bound = self.limits[limit].bound.min(bound);
+        if self.propagate_limit(limit).is_some() || self.propagate().is_some() {
+            self.unsatisfiable = true;
+        }
+    }
+
+    /// Makes false every unassigned term of `limit` that would take its total over the bound, returning a clause
+    /// with every literal false if the total is over the bound already.
+    fn propagate_limit(&mut self, limit: usize) -> Option<usize> {
+        let WeightLimit { terms, bound, sum } = &self.limits[limit];
+        if *sum > *bound as u128 {
+            let conflict = self.explain(limit, None);
+            return Some(self.attach_reason(conflict));
+        }
+
+        let slack = (*bound as u128 - sum) as u64;
+        let implied: Vec<Lit> = terms
+            .iter()
+            .take_while(|&&(_, weight)| weight > slack)
+            .filter(|&&(lit, _)| value(&self.values, lit) == 0)
+            .map(|&(lit, _)| !lit)
+            .collect();
+        for lit in implied {
+            // Level 0 assignments need no reason, and terms over the bound on their own are false from there on
+            let reason = match self.decision_level() {
+                0 => None,
+                _ => {
+                    let reason = self.explain(limit, Some(lit));
+                    Some(self.attach_reason(reason))
+                }
+            };
+            self.assign(lit, reason);
+        }
+        None
+    }
+
+    /// A clause that follows from `limit`: the heaviest of its true terms, enough to take the total over the bound
+    /// alone or together with the term `implied` makes false, each negated, after `implied` if any.
+    fn explain(&self, limit: usize, implied: Option<Lit>) -> Vec<Lit> {
+        let WeightLimit { terms, bound, .. } = &self.limits[limit];
+        let mut clause: Vec<Lit> = implied.into_iter().collect();
+        let mut total: u128 = implied.map_or(0, |lit| terms.iter().find(|&&(term, _)| term == !lit).map_or(0, |&(_, weight)| weight) as u128);
+        for &(lit, weight) in terms {
+            if total > *bound as u128 {
+                break;
+            }
+            if value(&self.values, lit) == 1 {
+                clause.push(!lit);
+                total += weight as u128;
+            }
+        }
+        clause
+    }
+
+    /// Stores a clause whose literals are all false but maybe the first, watching the first two once the others
+    /// are ordered from the latest decision level, and returns its index.
+    fn attach_reason(&mut self, mut clause: Vec<Lit>) -> usize {
+        let levels = &self.levels;
+        let start = if value(&self.values, clause[0]) == 0 { 1 } else { 0 };
+        clause[start..].sort_by_key(|lit| std::cmp::Reverse(levels[lit.var()]));
+        // Reasons of a single literal only come up at level 0, where they are never analyzed
+        if clause.len() < 2 {
+            self.clauses.push(clause);
+            return self.clauses.len() - 1;
+        }
+        self.attach(clause)
+    }
+
+    /// Stores a clause of at least two literals, watching its first two, and returns its index.
+    fn attach(&mut self, clause: Vec<Lit>) -> usize {
+        let index = self.clauses.len();
+        self.watches[clause[0].index()].push(index);
+        self.watches[clause[1].index()].push(index);
+        self.clauses.push(clause);
+        index
+    }
+
+    /// Assigns every literal implied by the assignments so far, returning a clause with every literal false
+    /// if there is a conflict.
+    fn propagate(&mut self) -> Option<usize> {
+        while self.propagated < self.trail.len() {
+            let false_lit = !self.trail[self.propagated];
+            self.propagated += 1;
+
+            let mut watching = std::mem::take(&mut self.watches[false_lit.index()]);
+            let mut kept = 0;
+            let mut conflict = None;
+            let mut next = 0;
+            while next < watching.len() {
+                let index = watching[next];
+                next += 1;
+                let clause = &mut self.clauses[index];
+                if clause[0] == false_lit {
+                    clause.swap(0, 1);
+                }
+
+                // Still satisfied by the other watched literal
+                if value(&self.values, clause[0]) == 1 {
+                    watching[kept] = index;
+                    kept += 1;
+                    continue;
+                }
+
+                // Watch another literal that isn't false instead
+                if let Some(other) = (2..clause.len()).find(|&k| value(&self.values, clause[k]) != -1) {
+                    clause.swap(1, other);
+                    self.watches[clause[1].index()].push(index);
+                    continue;
+                }
+
+                watching[kept] = index;
+                kept += 1;
+                if value(&self.values, clause[0]) == -1 {
+                    conflict = Some(index);
+                    while next < watching.len() {
+                        watching[kept] = watching[next];
+                        kept += 1;
+                        next += 1;
+                    }
+                } else {
+                    let implied = clause[0];
+                    self.assign(implied, Some(index));
+                }
+            }
+            watching.truncate(kept);
+            self.watches[false_lit.index()] = watching;
+
+            if conflict.is_some() {
+                return conflict;
+            }
+            for k in 0..self.weighted[(!false_lit).index()].len() {
+                let limit = self.weighted[(!false_lit).index()][k].0;
+                if let Some(conflict) = self.propagate_limit(limit) {
+                    return Some(conflict);
+                }
+            }
+        }
+        None
+    }
+
+    fn bump_activity(&mut self, var: usize) {
+        self.activity[var] += self.activity_increment;
+        if self.activity[var] > 1e100 {
+            for activity in self.activity.iter_mut() {
+                *activity *= 1e-100;
+            }
+            self.activity_increment *= 1e-100;
+            self.candidates = (0..self.values.len()).map(|var| Candidate { activity: self.activity[var], var }).collect();
+        } else {
+            self.candidates.push(Candidate { activity: self.activity[var], var });
+        }
+    }
+
+    /// Learns a clause from a conflict, keeping only the first unique implication point of the current level,
+    /// and returns it with the level to backtrack to.
+    fn analyze(&mut self, conflict: usize) -> (Vec<Lit>, usize) {
+        let mut seen = std::mem::take(&mut self.seen);
+        let mut learned = vec![Lit(0)];
+        let mut pending = 0;
+        let mut clause = conflict;
+        let mut implied: Option<Lit> = None;
+        let mut position = self.trail.len();
+
+        loop {
+            let start = if implied.is_some() { 1 } else { 0 };
+            for k in start..self.clauses[clause].len() {
+                let lit = self.clauses[clause][k];
+                let var = lit.var();
+                if !seen[var] && self.levels[var] > 0 {
+                    seen[var] = true;
+                    self.bump_activity(var);
+                    if self.levels[var] == self.decision_level() {
+                        pending += 1;
+                    } else {
+                        learned.push(lit);
+                    }
+                }
+            }
+
+            // The latest assignment of the current level involved in the conflict
+            loop {
+                position -= 1;
+                if seen[self.trail[position].var()] {
+                    break;
+                }
+            }
+            let lit = self.trail[position];
+            implied = Some(lit);
+            seen[lit.var()] = false;
+            pending -= 1;
+            if pending == 0 {
+                learned[0] = !lit;
+                break;
+            }
+            clause = self.reasons[lit.var()].expect("only decisions have no reason, and a level has one decision");
+        }
+
+        for lit in &learned {
+            seen[lit.var()] = false;
+        }
+        self.seen = seen;
+
+        // Backtrack to the second highest level, where the learned clause implies its first literal
+        let mut backtrack_level = 0;
+        if learned.len() > 1 {
+            let highest = (1..learned.len()).max_by_key(|&k| self.levels[learned[k].var()]).unwrap_or(1);
+            learned.swap(1, highest);
+            backtrack_level = self.levels[learned[1].var()];
+        }
+        (learned, backtrack_level)
+    }
+
+    /// Picks the most active unassigned variable, or `None` if every variable is assigned.
+    fn pick_branch(&mut self) -> Option<usize> {
+        while let Some(Candidate { activity, var }) = self.candidates.pop() {
+            if self.values[var] == 0 && activity == self.activity[var] {
+                return Some(var);
+            }
+        }
+        // Stale entries may hide unassigned variables
+        (0..self.values.len()).find(|&var| self.values[var] == 0)
+    }
+
+    /// Looks for a value of every variable satisfying every clause, returning `None` if there is none.
+    ///
+    /// `control` is checked at every decision and conflict. An interrupted search can be resumed by calling
+    /// `solve` again, keeping every clause learned so far.
+    pub fn solve(&mut self, control: &mut SearchControl) -> Result<Option<Vec<bool>>, Interruption> {
+        if self.unsatisfiable {
+            return Ok(None);
+        }
+        self.backtrack(0);
+        if self.propagate().is_some() {
+            self.unsatisfiable = true;
+            return Ok(None);
+        }
+
+        let mut restarts = 0;
+        let mut conflicts = 0;
+        loop {
+            control.check(Progress {
+                nodes: self.decisions,
+                backtracks: self.conflicts,
+                depth: self.trail.len(),
+                max_depth: self.values.len(),
+            })?;
+
+            if let Some(conflict) = self.propagate() {
+                if self.decision_level() == 0 {
+                    self.unsatisfiable = true;
+                    return Ok(None);
+                }
+                self.conflicts += 1;
+                let (learned, level) = self.analyze(conflict);
+                self.backtrack(level);
+                if learned.len() == 1 {
+                    self.assign(learned[0], None);
+                } else {
+                    let implied = learned[0];
+                    let index = self.attach(learned);
+                    self.assign(implied, Some(index));
+                }
+                self.activity_increment /= ACTIVITY_DECAY;
+
+                conflicts += 1;
+                if conflicts >= RESTART_BASE * luby(restarts) {
+                    restarts += 1;
+                    conflicts = 0;
+                    self.backtrack(0);
+                }
+            } else {
+                let Some(var) = self.pick_branch() else {
+                    return Ok(Some(self.values.iter().map(|&value| value == 1).collect()));
+                };
+                self.decisions += 1;
+                self.level_starts.push(self.trail.len());
+                self.assign(Lit::new(var, self.phases[var]), None);
+            }
+        }
+    }
+}
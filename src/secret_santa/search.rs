@@ -0,0 +1,526 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use crate::secret_santa::bitset::{transpose, BitSet};
+use crate::secret_santa::control::{Interruption, Progress, SearchControl};
+use crate::secret_santa::optimize::{min_cost_matching, Costs};
+use crate::secret_santa::trace::TraceEvent;
+
+/// One way of continuing a gift cycle.
+#[derive(Clone, Copy)]
+enum Step {
+    /// The end of the current cycle gives to this participant.
+    Give(usize),
+    /// The end of the current cycle gives back to its start, and a new cycle begins.
+    Close,
+}
+
+/// Depth-first search for gift cycles going through every participant, each at least `min_cycle_length` long.
+///
+/// The search builds cycles one after the other, extending each from a starting participant,
+/// and backtracks when it hits a dead end, so it either finds cycles or has ruled out every possibility.
+/// With costs, it keeps searching for cheaper cycles until none can be found (branch and bound).
+/// Participants are indexed from `0` to `n - 1`.
+struct CycleSearch<'g> {
+    can_give: Vec<BitSet>,
+    /// Who may give to each participant, the columns of `can_give`.
+    can_receive: Vec<BitSet>,
+    min_cycle_length: usize,
+    costs: Option<&'g Costs>,
+    /// Subtracted from every cost of each giver, so that no cost is negative.
+    cost_offsets: Vec<f64>,
+    /// Participants in the order they were reached, cycle after cycle.
+    path: Vec<usize>,
+    /// Where each cycle starts in `path`, the last one being the cycle currently being built.
+    cycle_starts: Vec<usize>,
+    /// Participants not in `path` yet.
+    unvisited: BitSet,
+    /// For each participant, how many of their allowed recipients haven't been visited yet.
+    recipients_left: Vec<usize>,
+    /// For each participant, how many of their allowed givers haven't been visited yet.
+    givers_left: Vec<usize>,
+    /// The steps left to try at each depth, the step that led there and the cost so far.
+    frames: Vec<(std::vec::IntoIter<Step>, Option<Step>, f64)>,
+    nodes: u64,
+    backtracks: u64,
+}
+
+impl<'g> CycleSearch<'g> {
+    fn new(allowed: &[BitSet], min_cycle_length: usize, costs: Option<&'g Costs>) -> Self {
+        let count = allowed.len();
+        let can_give = allowed.to_vec();
+        let can_receive = transpose(allowed);
+
+        let recipients_left = can_give.iter().map(BitSet::len).collect();
+        let givers_left = can_receive.iter().map(BitSet::len).collect();
+
+        // Every giver gives exactly once, so offsetting all of their costs doesn't change which cycles are cheapest
+        let cost_offsets = match costs {
+            Some(costs) => allowed
+                .iter()
+                .enumerate()
+                .map(|(giver, recipients)| recipients.iter().map(|r| costs.get(giver, r)).fold(0.0, f64::min))
+                .collect(),
+            None => vec![0.0; count],
+        };
+
+        Self {
+            can_give,
+            can_receive,
+            min_cycle_length,
+            costs,
+            cost_offsets,
+            path: Vec::with_capacity(count),
+            cycle_starts: Vec::new(),
+            unvisited: BitSet::full(count),
+            recipients_left,
+            givers_left,
+            frames: Vec::new(),
+            nodes: 0,
+            backtracks: 0,
+        }
+    }
+
+    fn visit(&mut self, participant: usize) {
+        self.unvisited.remove(participant);
+        self.path.push(participant);
+        for giver in self.can_receive[participant].iter() {
+            self.recipients_left[giver] -= 1;
+        }
+        for recipient in self.can_give[participant].iter() {
+            self.givers_left[recipient] -= 1;
+        }
+    }
+
+    fn leave(&mut self) {
+        let Some(participant) = self.path.pop() else { return };
+        self.unvisited.insert(participant);
+        for giver in self.can_receive[participant].iter() {
+            self.recipients_left[giver] += 1;
+        }
+        for recipient in self.can_give[participant].iter() {
+            self.givers_left[recipient] += 1;
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.can_give.len() - self.path.len()
+    }
+
+    fn cycle_start(&self) -> usize {
+        self.path[self.cycle_starts[self.cycle_starts.len() - 1]]
+    }
+
+    fn cycle_length(&self) -> usize {
+        self.path.len() - self.cycle_starts[self.cycle_starts.len() - 1]
+    }
+
+    fn end(&self) -> usize {
+        self.path[self.path.len() - 1]
+    }
+
+    fn cost(&self, giver: usize, recipient: usize) -> f64 {
+        self.costs.map_or(0.0, |costs| costs.get(giver, recipient) - self.cost_offsets[giver])
+    }
+
+    fn step_cost(&self, step: Step) -> f64 {
+        match step {
+            Step::Give(recipient) => self.cost(self.end(), recipient),
+            Step::Close => self.cost(self.end(), self.cycle_start()),
+        }
+    }
+
+    /// Starts a new cycle from the unvisited participant with the fewest options left.
+    fn open_cycle(&mut self) {
+        let start = self.unvisited.iter().min_by_key(|&p| self.recipients_left[p]);
+        if let Some(start) = start {
+            self.cycle_starts.push(self.path.len());
+            self.visit(start);
+        }
+    }
+
+    /// Undoes `open_cycle`, reopening the previous cycle.
+    fn reopen_cycle(&mut self) {
+        self.leave();
+        self.cycle_starts.pop();
+    }
+
+    /// Checks whether the cycles built so far can still be completed.
+    ///
+    /// Every participant left must still have someone to give to (another participant left, or the start
+    /// of the current cycle if they close it) and someone to receive from (another participant left, or the
+    /// end of the current cycle). Only one participant can close the current cycle, and only one can directly
+    /// follow its end.
+    fn can_complete(&self) -> bool {
+        let (start, end, remaining) = (self.cycle_start(), self.end(), self.remaining());
+
+        if self.cycle_length() + remaining < self.min_cycle_length {
+            return false;
+        }
+        if remaining == 0 {
+            return self.can_give[end].contains(start);
+        }
+
+        let mut closing = 0;
+        let mut following = 0;
+        for participant in self.unvisited.iter() {
+            if self.recipients_left[participant] == 0 {
+                if !self.can_give[participant].contains(start) {
+                    return false;
+                }
+                closing += 1;
+            }
+            if self.givers_left[participant] == 0 {
+                if !self.can_give[end].contains(participant) {
+                    return false;
+                }
+                following += 1;
+                // Following `end` and closing the cycle ends it, which must leave enough participants for the next cycles
+                if self.recipients_left[participant] == 0
+                    && remaining > 1
+                    && (self.cycle_length() + 1 < self.min_cycle_length || remaining - 1 < self.min_cycle_length)
+                {
+                    return false;
+                }
+            }
+        }
+
+        closing <= 1 && following <= 1
+    }
+
+    /// Checks whether the current cycle may be closed now, leaving enough participants for the next cycles.
+    fn can_close(&self) -> bool {
+        let remaining = self.remaining();
+        self.cycle_length() >= self.min_cycle_length
+            && remaining >= self.min_cycle_length
+            && self.can_give[self.end()].contains(self.cycle_start())
+            // Someone who can only give to the start of this cycle, or only receive from its end, must come first
+            && self.unvisited.iter().all(|p| self.recipients_left[p] > 0 && self.givers_left[p] > 0)
+    }
+
+    /// Lists the ways the current cycle may continue, in the order they should be tried.
+    ///
+    /// Candidates are shuffled, then those with the fewest onward options are tried first,
+    /// which finds cycles much sooner on sparse exclusion graphs. With costs, the cheapest are tried first.
+    fn candidates<R: Rng + ?Sized>(&self, rng: &mut R) -> Vec<Step> {
+        // A participant nobody else left can give to has to come next
+        if let Some(forced) = self.unvisited.iter().find(|&p| self.givers_left[p] == 0) {
+            return vec![Step::Give(forced)];
+        }
+
+        let mut recipients: Vec<usize> = self.can_give[self.end()].intersection(&self.unvisited).collect();
+        recipients.shuffle(rng);
+        recipients.sort_by_key(|&p| self.recipients_left[p]);
+
+        let mut candidates: Vec<Step> = recipients.into_iter().map(Step::Give).collect();
+        if self.can_close() {
+            let position = rng.gen_range(0..=candidates.len());
+            candidates.insert(position, Step::Close);
+        }
+        if self.costs.is_some() {
+            candidates.sort_by(|&a, &b| self.step_cost(a).total_cmp(&self.step_cost(b)));
+        }
+        candidates
+    }
+
+    fn take(&mut self, step: Step) {
+        match step {
+            Step::Give(recipient) => self.visit(recipient),
+            Step::Close => self.open_cycle(),
+        }
+    }
+
+    fn undo(&mut self, step: Step) {
+        match step {
+            Step::Give(_) => self.leave(),
+            Step::Close => self.reopen_cycle(),
+        }
+    }
+
+    /// Takes `step`, recording it in the trace of `control`.
+    fn push(&mut self, step: Step, control: &mut SearchControl) {
+        self.take(step);
+        control.record(TraceEvent::Push { participant: self.end(), new_cycle: matches!(step, Step::Close) });
+    }
+
+    /// Undoes `step`, recording it in the trace of `control`.
+    fn pop(&mut self, step: Step, control: &mut SearchControl) {
+        control.record(TraceEvent::Pop { participant: self.end() });
+        self.undo(step);
+    }
+
+    /// Adds a frame trying `candidates` after `step`, recording the choice in the trace of `control`.
+    fn choose(&mut self, candidates: Vec<Step>, step: Option<Step>, cost: f64, control: &mut SearchControl) {
+        control.record(TraceEvent::Choose { options: candidates.len() });
+        self.frames.push((candidates.into_iter(), step, cost));
+    }
+
+    /// Lists the recipient of each giver, closing every cycle back to its start.
+    fn recipients(&self) -> Vec<usize> {
+        let mut recipient_of = vec![0; self.path.len()];
+        for (index, &start) in self.cycle_starts.iter().enumerate() {
+            let end = self.cycle_starts.get(index + 1).copied().unwrap_or(self.path.len());
+            let cycle = &self.path[start..end];
+            for (&giver, &recipient) in cycle.iter().zip(cycle.iter().cycle().skip(1)) {
+                recipient_of[giver] = recipient;
+            }
+        }
+        recipient_of
+    }
+
+    /// Opens the first cycle, returning whether any assignment might exist.
+    fn start<R: Rng + ?Sized>(&mut self, control: &mut SearchControl, rng: &mut R) -> bool {
+        self.open_cycle();
+        control.record(TraceEvent::Push { participant: self.end(), new_cycle: true });
+        if !self.can_complete() {
+            return false;
+        }
+        let candidates = self.candidates(rng);
+        self.choose(candidates, None, 0.0, control);
+        true
+    }
+
+    /// Resumes the search until the next assignment cheaper than `bound`, returning it with its cost.
+    ///
+    /// Branches that already cost `bound` or more are skipped. Once this returns `None`, every
+    /// possibility has been explored. `control` may interrupt the search at any node.
+    fn next_assignment<R: Rng + ?Sized>(
+        &mut self,
+        bound: f64,
+        control: &mut SearchControl,
+        rng: &mut R,
+    ) -> Result<Option<(Vec<usize>, f64)>, Interruption> {
+        while let Some((frame, _, cost)) = self.frames.last_mut() {
+            let cost = *cost;
+            match frame.next() {
+                Some(step) => {
+                    // Costs are never negative, so this can't get any cheaper than `bound`
+                    let cost = cost + self.step_cost(step);
+                    if cost >= bound {
+                        continue;
+                    }
+
+                    self.push(step, control);
+                    self.nodes += 1;
+                    control.check(Progress {
+                        nodes: self.nodes,
+                        backtracks: self.backtracks,
+                        depth: self.path.len(),
+                        max_depth: self.can_give.len(),
+                    })?;
+
+                    if !self.can_complete() {
+                        self.pop(step, control);
+                    } else if self.remaining() == 0 {
+                        let total = cost + self.cost(self.end(), self.cycle_start());
+                        let recipient_of = self.recipients();
+                        self.pop(step, control);
+                        if total < bound {
+                            return Ok(Some((recipient_of, total)));
+                        }
+                    } else {
+                        let candidates = self.candidates(rng);
+                        self.choose(candidates, Some(step), cost, control);
+                    }
+                }
+                None => {
+                    // Every way of continuing failed, backtrack
+                    if let Some((_, Some(step), _)) = self.frames.pop() {
+                        self.pop(step, control);
+                        self.backtracks += 1;
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs the search, stopping early once an assignment costs no more than `lower_bound`.
+    ///
+    /// If `control` stops the search for any other reason than a cancellation,
+    /// the cheapest assignment found so far is better than nothing.
+    fn run<R: Rng + ?Sized>(
+        &mut self,
+        lower_bound: f64,
+        control: &mut SearchControl,
+        rng: &mut R,
+    ) -> Result<Option<Vec<usize>>, Interruption> {
+        if !self.start(control, rng) {
+            return Ok(None);
+        }
+
+        let mut best: Option<(Vec<usize>, f64)> = None;
+        loop {
+            let bound = best.as_ref().map_or(f64::INFINITY, |(_, cost)| *cost);
+            match self.next_assignment(bound, control, rng) {
+                Ok(Some((recipient_of, total))) => {
+                    best = Some((recipient_of, total));
+                    if self.costs.is_none() || total <= lower_bound + 1e-9 {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(interruption) if interruption == Interruption::Cancelled || best.is_none() => return Err(interruption),
+                Err(_) => break,
+            }
+        }
+
+        Ok(best.map(|(recipient_of, _)| recipient_of))
+    }
+
+    /// Walks down the search tree once at random, following only branches that can still be completed.
+    ///
+    /// Returns the base 10 logarithm of the product of the number of branches met at each level if the walk
+    /// reaches an assignment, and `None` otherwise. Averaged over many walks, that product is an unbiased
+    /// estimate of the number of assignments (Knuth's estimator), since every assignment is one leaf of the tree.
+    fn probe<R: Rng + ?Sized>(&mut self, rng: &mut R) -> Option<f64> {
+        self.open_cycle();
+        let mut taken = Vec::new();
+        let mut log10 = 0.0;
+        let reached = loop {
+            if !self.can_complete() {
+                break false;
+            }
+            if self.remaining() == 0 {
+                break true;
+            }
+            let branches: Vec<Step> = self
+                .candidates(rng)
+                .into_iter()
+                .filter(|&step| {
+                    self.take(step);
+                    let possible = self.can_complete();
+                    self.undo(step);
+                    possible
+                })
+                .collect();
+            let Some(&step) = branches.choose(rng) else { break false };
+            log10 += (branches.len() as f64).log10();
+            self.take(step);
+            taken.push(step);
+        };
+
+        for step in taken.into_iter().rev() {
+            self.undo(step);
+        }
+        self.reopen_cycle();
+        reached.then_some(log10)
+    }
+}
+
+/// Checks that every participant can reach every other one by following allowed gifts,
+/// which any single gift cycle requires.
+fn is_strongly_connected(allowed: &[BitSet]) -> bool {
+    let reaches_all = |edges: &[BitSet]| {
+        let mut reached = vec![false; edges.len()];
+        let mut queue = VecDeque::from([0]);
+        reached[0] = true;
+        while let Some(participant) = queue.pop_front() {
+            for next in edges[participant].iter() {
+                if !reached[next] {
+                    reached[next] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+        reached.into_iter().all(|r| r)
+    };
+
+    reaches_all(allowed) && reaches_all(&transpose(allowed))
+}
+
+/// Finds gift cycles going through every participant, each at least `min_cycle_length` participants long,
+/// where `allowed[giver]` is the set of recipients that `giver` may give to.
+///
+/// Returns the recipient of each giver, or `None` if no such cycles exist. The search is exhaustive,
+/// so `None` means that no valid assignment exists. A `min_cycle_length` of `n` asks for a single cycle.
+/// With `costs`, the returned assignment is one of the cheapest, or the cheapest found before `control`
+/// ran out of time or nodes.
+pub fn find_cycles<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    min_cycle_length: usize,
+    costs: Option<&Costs>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<usize>>, Interruption> {
+    let count = allowed.len();
+    if count < 2 || min_cycle_length > count || (min_cycle_length == count && !is_strongly_connected(allowed)) {
+        return Ok(None);
+    }
+
+    let mut search = CycleSearch::new(allowed, min_cycle_length.max(2), costs);
+
+    // No cycles can be cheaper than the cheapest assignment ignoring cycle lengths
+    let lower_bound = match costs {
+        Some(costs) => {
+            let Some(cheapest) = min_cost_matching(allowed, costs) else { return Ok(None) };
+            (0..count).map(|giver| search.cost(giver, cheapest[giver])).sum()
+        }
+        None => 0.0,
+    };
+    search.run(lower_bound, control, rng)
+}
+
+/// Estimates how many sets of gift cycles go through every participant, each at least `min_cycle_length`
+/// participants long, from `probes` random walks down the search tree.
+///
+/// Returns the base 10 logarithm of the estimate, as counts quickly outgrow any number type,
+/// or `None` if no walk found an assignment.
+pub fn estimate_cycles<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    min_cycle_length: usize,
+    probes: usize,
+    rng: &mut R,
+) -> Option<f64> {
+    let count = allowed.len();
+    if count < 2 || min_cycle_length > count || (min_cycle_length == count && !is_strongly_connected(allowed)) {
+        return None;
+    }
+
+    let mut search = CycleSearch::new(allowed, min_cycle_length.max(2), None);
+    let found: Vec<f64> = (0..probes).filter_map(|_| search.probe(rng)).collect();
+
+    // Average `10^log10` over every probe without overflowing, failed probes counting as zero
+    if found.is_empty() {
+        return None;
+    }
+    let largest = found.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let sum: f64 = found.iter().map(|&log10| 10f64.powf(log10 - largest)).sum();
+    Some(largest + sum.log10() - (probes as f64).log10())
+}
+
+/// Lazily lists every set of gift cycles going through every participant, each at least `min_cycle_length`
+/// participants long, as the recipient of each giver.
+///
+/// Every assignment comes up exactly once, in an order that only depends on `allowed`.
+pub struct Enumeration {
+    search: CycleSearch<'static>,
+    control: SearchControl<'static>,
+    rng: StdRng,
+}
+
+impl Enumeration {
+    pub fn new(allowed: &[BitSet], min_cycle_length: usize) -> Self {
+        let mut enumeration = Self {
+            search: CycleSearch::new(allowed, min_cycle_length.max(2), None),
+            control: SearchControl::default(),
+            rng: StdRng::seed_from_u64(0),
+        };
+        let count = allowed.len();
+        if count >= 2 && min_cycle_length <= count {
+            enumeration.search.start(&mut enumeration.control, &mut enumeration.rng);
+        }
+        enumeration
+    }
+}
+
+impl Iterator for Enumeration {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The default control never interrupts the search
+        let next = self.search.next_assignment(f64::INFINITY, &mut self.control, &mut self.rng);
+        next.ok().flatten().map(|(recipient_of, _)| recipient_of)
+    }
+}
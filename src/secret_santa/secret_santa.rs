@@ -0,0 +1,969 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use crate::secret_santa::bitset::{rename, BitSet};
+use crate::secret_santa::constraints::{Constraints, DrawMode};
+use crate::secret_santa::control::{Interruption, SearchControl};
+use crate::secret_santa::counting::{count_matchings, count_single_cycles, AssignmentCount};
+use crate::secret_santa::draw_error::DrawError;
+use crate::secret_santa::history::HistoryPolicy;
+use crate::secret_santa::matching::{hall_violator, maximum_matching, perfect_matchings, regular_assignment};
+use crate::secret_santa::optimize::{min_cost_matching, Costs};
+use crate::secret_santa::relaxation::relaxations;
+use crate::secret_santa::sampling::{markov_chain_sample, rejection_sample, Rules, SamplingMethod};
+use crate::secret_santa::solver::{sat_rounds, DrawSolver};
+use crate::secret_santa::search::{estimate_cycles, find_cycles, Enumeration};
+use crate::secret_santa::trace::Trace;
+use crate::secret_santa::violation::Violation;
+
+/// A draw with a late joiner spliced in.
+pub(crate) struct LateJoin<'a, T> {
+    pub assignment: Assignment<'a, T>,
+    /// Givers of the previous draw whose recipient changed, who have to be told their new recipient.
+    pub renotified: Vec<&'a T>,
+}
+
+/// A draw for events where gifts are passed along a single open chain, one after the other.
+pub(crate) struct Chain<'a, T> {
+    /// Participants in the order gifts are passed: the first one starts, and each one gives to the next.
+    pub order: Vec<&'a T>,
+    /// Whether the last participant may give to the first, closing the chain, if the event wants to.
+    pub closes: bool,
+}
+
+impl<'a, T> Chain<'a, T> {
+    /// The handovers of the live event in the order gifts are revealed, as giver and recipient,
+    /// ending with the last participant giving to the first when the chain closes.
+    pub fn reveal_order(&self) -> Vec<(&'a T, &'a T)> {
+        let mut reveals: Vec<(&'a T, &'a T)> = self.order.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        if let (true, Some(&first), Some(&last)) = (self.closes, self.order.first(), self.order.last()) {
+            reveals.push((last, first));
+        }
+        reveals
+    }
+}
+
+/// Largest derangements without cycle length rules whose assignments are counted exactly.
+const EXACT_MATCHING_COUNT_LIMIT: usize = 20;
+/// Largest single cycle draws whose assignments are counted exactly.
+const EXACT_SINGLE_CYCLE_COUNT_LIMIT: usize = 16;
+/// Largest draws with other rules whose assignments are counted exactly, by listing them all.
+const EXACT_SEARCH_COUNT_LIMIT: usize = 9;
+/// Random walks down the search tree used to estimate the number of assignments of larger events.
+const COUNT_ESTIMATE_PROBES: usize = 200;
+
+/// A Secret Santa assignment, mapping every giver to their recipient.
+pub(crate) type Assignment<'a, T> = HashMap<&'a T, &'a T>;
+
+/// Exclusions to drop so that a draw becomes possible, as the giver and recipient of each.
+pub(crate) type Relaxation<'a, T> = Vec<(&'a T, &'a T)>;
+
+/// A draw together with the seed that reproduces it.
+pub(crate) struct Draw<'a, T> {
+    pub seed: u64,
+    pub assignment: Assignment<'a, T>,
+    /// Total cost of the assignment, see [`Constraints::cost_of`].
+    pub cost: f64,
+}
+
+/// Sorts and deduplicates participants so that draws never depend on the iteration order of the input.
+fn canonical_order<'a, C, T>(participants: C) -> Vec<&'a T>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Ord,
+{
+    let mut ordered: Vec<&'a T> = participants.into_iter().collect();
+    ordered.sort();
+    ordered.dedup();
+    ordered
+}
+
+/// The set of participants each participant may give to, as indices into `participants`.
+///
+/// Exclusions are looked up once each rather than once per pair, which matters for events with thousands of participants.
+fn allowed_recipients<'a, T>(participants: &[&'a T], constraints: &Constraints<'a, T>) -> Vec<BitSet>
+where
+    T: Eq + Hash,
+{
+    let count = participants.len();
+    let index: HashMap<&'a T, usize> = participants.iter().enumerate().map(|(i, &participant)| (participant, i)).collect();
+    let mut allowed: Vec<BitSet> = (0..count)
+        .map(|giver| {
+            let mut recipients = BitSet::full(count);
+            recipients.remove(giver);
+            recipients
+        })
+        .collect();
+    let mut exclude = |giver: &T, recipient: &T| {
+        if let (Some(&giver), Some(&recipient)) = (index.get(giver), index.get(recipient)) {
+            allowed[giver].remove(recipient);
+        }
+    };
+
+    for (&giver, excluded) in &constraints.exclusions {
+        for &recipient in excluded {
+            exclude(giver, recipient);
+        }
+    }
+    if let Some((history, HistoryPolicy::Exclude { years })) = &constraints.history {
+        for (_, giver, recipient) in history.pairs().filter(|&(age, _, _)| age <= *years) {
+            exclude(giver, recipient);
+        }
+    }
+
+    allowed
+}
+
+/// Gathers the cost of each pair of participants, or `None` if every pair is equally good.
+pub(super) fn pair_costs<'a, T>(participants: &[&'a T], constraints: &Constraints<'a, T>) -> Option<Costs>
+where
+    T: Eq + Hash,
+{
+    let index: HashMap<&'a T, usize> = participants.iter().enumerate().map(|(i, &participant)| (participant, i)).collect();
+    let mut costs = Costs::default();
+
+    for (&(giver, recipient), &cost) in &constraints.pair_costs {
+        if let (Some(&giver), Some(&recipient)) = (index.get(giver), index.get(recipient)) {
+            costs.add(giver, recipient, cost);
+        }
+    }
+
+    if let Some((history, HistoryPolicy::Penalize { decay })) = &constraints.history {
+        for (age, giver, recipient) in history.pairs() {
+            if let (Some(&giver), Some(&recipient)) = (index.get(giver), index.get(recipient)) {
+                costs.add(giver, recipient, decay.powi(age as i32 - 1));
+            }
+        }
+    }
+
+    (!costs.is_empty()).then_some(costs)
+}
+
+/// Finds the pairs `constraints` requires, as indices into `participants`, checking that every giver and
+/// recipient is a participant, that no one gives or receives more than `gifts` required gifts, that required
+/// pairs are allowed, and, with single gifts, that they close no cycle shorter than the cycle rules allow.
+fn requirements<'a, T>(
+    participants: &[&'a T],
+    allowed: &[BitSet],
+    constraints: &Constraints<'a, T>,
+    gifts: usize,
+) -> Result<Vec<(usize, usize)>, DrawError<'a, T>>
+where
+    T: Eq + Hash,
+{
+    let index: HashMap<&'a T, usize> = participants.iter().enumerate().map(|(i, &participant)| (participant, i)).collect();
+    let mut required = Vec::new();
+    let mut required_from: HashMap<usize, Vec<(&'a T, &'a T)>> = HashMap::new();
+    let mut required_to: HashMap<usize, Vec<(&'a T, &'a T)>> = HashMap::new();
+
+    for &(giver, recipient) in &constraints.required {
+        let giver_index = *index.get(giver).ok_or(DrawError::UnknownParticipant(giver))?;
+        let recipient_index = *index.get(recipient).ok_or(DrawError::UnknownParticipant(recipient))?;
+        if required.contains(&(giver_index, recipient_index)) {
+            continue;
+        }
+
+        for pairs in [required_from.entry(giver_index).or_default(), required_to.entry(recipient_index).or_default()] {
+            if pairs.len() == gifts {
+                return Err(DrawError::ConflictingRequirements { first: pairs[0], second: (giver, recipient) });
+            }
+            pairs.push((giver, recipient));
+        }
+        if !allowed[giver_index].contains(recipient_index) {
+            return Err(DrawError::RequiredPairExcluded { giver, recipient });
+        }
+        required.push((giver_index, recipient_index));
+    }
+
+    // Single gifts make required pairs into chains, which must not loop back on themselves too soon
+    if gifts == 1 {
+        let min_cycle_length = min_cycle_length(participants.len(), constraints);
+        let next: HashMap<usize, usize> = required.iter().copied().collect();
+        for &(start, _) in &required {
+            let mut cycle = vec![start];
+            while let Some(&following) = next.get(&cycle[cycle.len() - 1]) {
+                if following == start {
+                    if cycle.len() < min_cycle_length {
+                        return Err(DrawError::RequiredCycleTooShort(cycle.into_iter().map(|p| participants[p]).collect()));
+                    }
+                    break;
+                }
+                cycle.push(following);
+            }
+        }
+    }
+
+    Ok(required)
+}
+
+/// Restricts `allowed` so that every required giver gives to their required recipient, and nobody else does.
+fn apply_requirements(allowed: &mut [BitSet], required: &[(usize, usize)]) {
+    for &(giver, recipient) in required {
+        for recipients in allowed.iter_mut() {
+            recipients.remove(recipient);
+        }
+        allowed[giver] = BitSet::new(allowed.len());
+        allowed[giver].insert(recipient);
+    }
+}
+
+/// The set of participants each participant may give a single gift to, as indices into `participants`, taking
+/// required pairs into account.
+pub(super) fn restricted_recipients<'a, T>(participants: &[&'a T], constraints: &Constraints<'a, T>) -> Result<Vec<BitSet>, DrawError<'a, T>>
+where
+    T: Eq + Hash,
+{
+    let mut allowed = allowed_recipients(participants, constraints);
+    let required = requirements(participants, &allowed, constraints, 1)?;
+    apply_requirements(&mut allowed, &required);
+    Ok(allowed)
+}
+
+/// Checks the conditions every draw needs, naming the participants that make the draw impossible.
+///
+/// Passing these checks means every participant can be given a distinct recipient, though not necessarily
+/// in a single gift cycle.
+pub(super) fn check_feasibility<'a, T>(participants: &[&'a T], allowed: &[BitSet]) -> Result<(), DrawError<'a, T>> {
+    if participants.len() < 2 {
+        return Err(DrawError::TooFewParticipants { count: participants.len(), needed: 2 });
+    }
+
+    if let Some(giver) = allowed.iter().position(BitSet::is_empty) {
+        return Err(DrawError::NoAllowedRecipients(participants[giver]));
+    }
+
+    let mut has_giver = BitSet::new(participants.len());
+    for recipients in allowed {
+        has_giver.union_with(recipients);
+    }
+    if let Some(recipient) = (0..participants.len()).find(|&recipient| !has_giver.contains(recipient)) {
+        return Err(DrawError::NoAllowedGivers(participants[recipient]));
+    }
+
+    let matching = maximum_matching(allowed);
+    if let Some((givers, recipients)) = hall_violator(allowed, &matching) {
+        return Err(DrawError::HallViolation {
+            givers: givers.into_iter().map(|giver| participants[giver]).collect(),
+            recipients: recipients.into_iter().map(|recipient| participants[recipient]).collect(),
+        });
+    }
+
+    Ok(())
+}
+
+/// A random renaming of `count` participants, and the renaming back. Matchings try recipients in increasing
+/// order, so renaming recipients at random first makes draws vary.
+fn random_names<R: Rng + ?Sized>(count: usize, rng: &mut R) -> (Vec<usize>, Vec<usize>) {
+    let mut names: Vec<usize> = (0..count).collect();
+    names.shuffle(rng);
+    let mut original = vec![0; count];
+    for (participant, &name) in names.iter().enumerate() {
+        original[name] = participant;
+    }
+    (names, original)
+}
+
+/// Draws a random perfect matching among `allowed`, one of the cheapest if there are `costs`, as the recipient
+/// of each giver, or `None` if there is none.
+pub(super) fn random_matching<R: Rng + ?Sized>(allowed: &[BitSet], costs: Option<&Costs>, rng: &mut R) -> Option<Vec<usize>> {
+    let (names, original) = random_names(allowed.len(), rng);
+    let renamed = rename(allowed, &names);
+    let recipient_of = match costs {
+        Some(costs) => min_cost_matching(&renamed, &costs.rename_recipients(&names))?,
+        None => maximum_matching(&renamed).recipient_of.into_iter().collect::<Option<_>>()?,
+    };
+    Some(recipient_of.into_iter().map(|recipient| original[recipient]).collect())
+}
+
+/// Generates a Secret Santa pairing, ensuring constraints are respected.
+pub(crate) fn generate_secret_santa<'a, C, T>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+{
+    let seed = rand::thread_rng().gen();
+    generate_secret_santa_seeded(participants, constraints, seed).map(|draw| draw.assignment)
+}
+
+/// Generates a Secret Santa pairing from a seed, recording the seed in the returned draw.
+///
+/// The same participants, constraints and seed always yield the same pairing.
+pub(crate) fn generate_secret_santa_seeded<'a, C, T>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    seed: u64,
+) -> Result<Draw<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+{
+    let mut rng = StdRng::seed_from_u64(seed);
+    generate_secret_santa_with_rng(participants, constraints, &mut rng)
+        .map(|assignment| Draw { seed, cost: constraints.cost_of(&assignment), assignment })
+}
+
+/// Draws the recipient of each participant of `participants`, which must be in canonical order.
+fn solve<'a, T, R>(
+    participants: &[&'a T],
+    constraints: &Constraints<'a, T>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<usize>, DrawError<'a, T>>
+where
+    T: Eq + Hash,
+    R: Rng + ?Sized,
+{
+    let allowed = control.time_phase("constraints", |_| restricted_recipients(participants, constraints))?;
+    let costs = pair_costs(participants, constraints);
+    solve_with(participants, constraints, allowed, costs.as_ref(), control, rng)
+}
+
+/// Draws the recipient of each participant of `participants` among `allowed`, with the cheapest total cost
+/// if there are `costs`, following the mode and cycle rules of `constraints`.
+fn solve_with<'a, T, R>(
+    participants: &[&'a T],
+    constraints: &Constraints<'a, T>,
+    allowed: Vec<BitSet>,
+    costs: Option<&Costs>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<usize>, DrawError<'a, T>>
+where
+    R: Rng + ?Sized,
+{
+    control.time_phase("feasibility", |_| check_feasibility(participants, &allowed))?;
+
+    // Any perfect matching is a valid derangement, only cycle length rules need a search
+    control.time_phase("search", |control| match constraints.mode {
+        DrawMode::SingleCycle => find_cycles(&allowed, participants.len(), costs, control, rng),
+        DrawMode::Derangement if constraints.min_cycle_length <= 2 => Ok(random_matching(&allowed, costs, rng)),
+        DrawMode::Derangement => find_cycles(&allowed, constraints.min_cycle_length, costs, control, rng),
+    })
+    .map_err(DrawError::Interrupted)?
+    .ok_or(DrawError::SearchExhausted)
+}
+
+/// Pairs every participant with their recipient, given as an index into `participants`.
+pub(super) fn to_assignment<'a, T: Eq + Hash>(participants: &[&'a T], recipient_of: Vec<usize>) -> Assignment<'a, T> {
+    recipient_of
+        .into_iter()
+        .enumerate()
+        .map(|(giver, recipient)| (participants[giver], participants[recipient]))
+        .collect()
+}
+
+/// Generates a Secret Santa pairing using the given random number generator.
+///
+/// Participants are put in a canonical order before drawing, so a deterministic `rng`
+/// gives a deterministic pairing regardless of `HashSet`/`HashMap` iteration order.
+pub(crate) fn generate_secret_santa_with_rng<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    rng: &mut R,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    generate_secret_santa_with_control(participants, constraints, &mut SearchControl::default(), rng)
+}
+
+/// Generates a Secret Santa pairing using the given random number generator, within the limits of `control`.
+///
+/// When there are costs and the search runs out of time or nodes, the cheapest pairing found so far is returned.
+/// Otherwise, a search stopped before finding any pairing fails with [`DrawError::Interrupted`].
+pub(crate) fn generate_secret_santa_with_control<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    // Work on indices into `participants` so every choice happens in a fixed order
+    let participants = canonical_order(participants);
+    let recipient_of = solve(&participants, constraints, control, rng)?;
+    Ok(to_assignment(&participants, recipient_of))
+}
+
+/// Picks a Secret Santa pairing uniformly at random among all the valid ones, using the given random number generator.
+///
+/// The search behind [`generate_secret_santa_with_rng`] makes some valid pairings more likely than others,
+/// while here every one of them is equally likely: exactly with [`SamplingMethod::Rejection`], and more and more
+/// closely as steps are added with [`SamplingMethod::MarkovChain`]. Costs are not taken into account. `control`
+/// limits the attempts, the search for a starting pairing and the steps alike.
+pub(crate) fn sample_secret_santa_with_rng<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    method: SamplingMethod,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    let participants = canonical_order(participants);
+    let allowed = restricted_recipients(&participants, constraints)?;
+    check_feasibility(&participants, &allowed)?;
+    let rules = Rules::new(&allowed, constraints.mode == DrawMode::SingleCycle, constraints.min_cycle_length);
+
+    let recipient_of = match method {
+        SamplingMethod::Rejection { attempts } => {
+            let sampled = rejection_sample(&rules, attempts, control, rng).map_err(DrawError::Interrupted)?;
+            sampled.ok_or(DrawError::SamplingFailed { attempts })?
+        }
+        SamplingMethod::MarkovChain { steps } => {
+            let start = solve(&participants, constraints, control, rng)?;
+            markov_chain_sample(&rules, start, steps, control, rng).map_err(DrawError::Interrupted)?
+        }
+    };
+    Ok(to_assignment(&participants, recipient_of))
+}
+
+/// Generates a Secret Santa draw where every participant gives `gifts` gifts and receives `gifts` gifts,
+/// never to or from the same person twice, using the given random number generator.
+///
+/// Exclusions and history exclusions are respected. The draw mode, cycle lengths and costs are not taken into
+/// account, as gifts form no single cycle here. `gifts` must be at least one. Like [`generate_secret_santa_with_rng`],
+/// a deterministic `rng` gives a deterministic draw.
+pub(crate) fn generate_secret_santa_with_gifts<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    gifts: usize,
+    rng: &mut R,
+) -> Result<HashMap<&'a T, Vec<&'a T>>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    if gifts == 0 {
+        return Err(DrawError::NoGifts);
+    }
+    let participants = canonical_order(participants);
+    if participants.len() <= gifts {
+        return Err(DrawError::TooFewParticipants { count: participants.len(), needed: gifts + 1 });
+    }
+
+    let allowed = allowed_recipients(&participants, constraints);
+    let required = requirements(&participants, &allowed, constraints, gifts)?;
+    check_feasibility(&participants, &allowed)?;
+
+    let (names, original) = random_names(participants.len(), rng);
+    let required: Vec<(usize, usize)> = required.into_iter().map(|(giver, recipient)| (giver, names[recipient])).collect();
+    let recipients_of = regular_assignment(&rename(&allowed, &names), gifts, &required).ok_or(DrawError::SearchExhausted)?;
+    Ok(recipients_of
+        .into_iter()
+        .enumerate()
+        .map(|(giver, recipients)| {
+            (participants[giver], recipients.into_iter().map(|r| participants[original[r]]).collect())
+        })
+        .collect())
+}
+
+/// Generates `rounds` Secret Santa pairings of the same participants, such as one per gift of a season, where
+/// nobody gives to the same recipient twice, using the given random number generator, within the limits of
+/// `control`.
+///
+/// Every round follows the draw mode, cycle rules, exclusions and history exclusions of `constraints`. All the
+/// rounds are drawn together, so that early rounds never use up pairs later rounds need. Required pairs are all
+/// part of the first round, and left free in the others since no pair repeats. Costs are not taken into account.
+/// Like [`generate_secret_santa_with_rng`], a deterministic `rng` gives deterministic rounds.
+pub(crate) fn generate_secret_santa_rounds<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    rounds: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<Assignment<'a, T>>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    let participants = canonical_order(participants);
+    if participants.len() <= rounds {
+        return Err(DrawError::TooFewParticipants { count: participants.len(), needed: rounds + 1 });
+    }
+
+    let allowed = allowed_recipients(&participants, constraints);
+    let required = requirements(&participants, &allowed, constraints, 1)?;
+    let mut first_round = allowed.clone();
+    apply_requirements(&mut first_round, &required);
+    check_feasibility(&participants, &first_round)?;
+
+    // Without cycle length rules nor required pairs, any split of a regular assignment into perfect matchings
+    // gives valid rounds
+    let recipients_of = match constraints.mode {
+        DrawMode::Derangement if constraints.min_cycle_length <= 2 && required.is_empty() => {
+            let (names, original) = random_names(participants.len(), rng);
+            regular_assignment(&rename(&allowed, &names), rounds, &[]).map(|recipients_of| {
+                let rounds = perfect_matchings(&recipients_of);
+                rounds.into_iter().map(|round| round.into_iter().map(|r| original[r]).collect()).collect()
+            })
+        }
+        _ => {
+            let min = min_cycle_length(participants.len(), constraints);
+            sat_rounds(&allowed, min, rounds, &required, control, rng).map_err(DrawError::Interrupted)?
+        }
+    };
+    Ok(recipients_of
+        .ok_or(DrawError::SearchExhausted)?
+        .into_iter()
+        .map(|recipient_of| to_assignment(&participants, recipient_of))
+        .collect())
+}
+
+/// The shortest gift cycle allowed by `constraints` among `count` participants.
+pub(super) fn min_cycle_length<T>(count: usize, constraints: &Constraints<'_, T>) -> usize {
+    match constraints.mode {
+        DrawMode::SingleCycle => count,
+        DrawMode::Derangement => constraints.min_cycle_length.max(2),
+    }
+}
+
+/// Counts the valid Secret Santa pairings, using the given random number generator for estimates.
+///
+/// Small events are counted exactly: derangements without cycle length rules as the permanent of the matrix of
+/// allowed pairs, single cycles by counting paths through every set of participants, and anything else by
+/// exhaustive search. Larger events get an estimate, which varies with `rng`. Costs are not taken into account,
+/// as they only make some valid pairings preferable. A small count means the draw is easy to guess.
+pub(crate) fn count_secret_santa_with_rng<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    rng: &mut R,
+) -> AssignmentCount
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    let participants = canonical_order(participants);
+    let Ok(allowed) = restricted_recipients(&participants, constraints) else {
+        return AssignmentCount::Exact(0);
+    };
+    if check_feasibility(&participants, &allowed).is_err() {
+        return AssignmentCount::Exact(0);
+    }
+
+    let count = participants.len();
+    let min_cycle_length = min_cycle_length(count, constraints);
+    match constraints.mode {
+        DrawMode::SingleCycle if count <= EXACT_SINGLE_CYCLE_COUNT_LIMIT => {
+            AssignmentCount::Exact(count_single_cycles(&allowed))
+        }
+        DrawMode::Derangement if min_cycle_length <= 2 && count <= EXACT_MATCHING_COUNT_LIMIT => {
+            AssignmentCount::Exact(count_matchings(&allowed))
+        }
+        _ if count <= EXACT_SEARCH_COUNT_LIMIT => {
+            AssignmentCount::Exact(Enumeration::new(&allowed, min_cycle_length).count() as u128)
+        }
+        _ => AssignmentCount::Estimated {
+            log10: estimate_cycles(&allowed, min_cycle_length, COUNT_ESTIMATE_PROBES, rng).unwrap_or(f64::NEG_INFINITY),
+        },
+    }
+}
+
+/// Lazily lists every valid Secret Santa pairing, each exactly once.
+///
+/// The order only depends on the participants and constraints. Costs are not taken into account.
+pub(crate) fn enumerate_secret_santa<'a, C, T>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+) -> impl Iterator<Item = Assignment<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+{
+    let participants = canonical_order(participants);
+    // Conflicting requirements leave nobody to enumerate
+    let allowed = restricted_recipients(&participants, constraints).unwrap_or_default();
+    let min_cycle_length = min_cycle_length(participants.len(), constraints);
+    Enumeration::new(&allowed, min_cycle_length).map(move |recipient_of| to_assignment(&participants, recipient_of))
+}
+
+/// Suggests up to `limit` sets of exclusions to drop so that a draw becomes possible, fewest exclusions first,
+/// using the given random number generator to break ties.
+///
+/// The first set is one of the smallest possible, and the others offer alternatives that keep one of its
+/// exclusions. Exclusions include those from groups and history, and required pairings are kept. A possible draw gets a single empty suggestion,
+/// and a draw that no dropped exclusion could make possible, such as one with a single participant, gets none.
+///
+/// Searches run within the limits of `control`. Once it stops them, the suggestions found so far are returned,
+/// or the interruption if there are none yet.
+pub(crate) fn suggest_relaxations_with_rng<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    limit: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Vec<Relaxation<'a, T>>, Interruption>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    let participants = canonical_order(participants);
+    let count = participants.len();
+    let allowed = allowed_recipients(&participants, constraints);
+
+    // Required pairs are checked as if nothing were excluded, as dropping exclusions can't fix anything else
+    let everyone: Vec<BitSet> = (0..count)
+        .map(|giver| {
+            let mut recipients = BitSet::full(count);
+            recipients.remove(giver);
+            recipients
+        })
+        .collect();
+    let Ok(required) = requirements(&participants, &everyone, constraints, 1) else {
+        return Ok(Vec::new());
+    };
+    let kept = (0..count)
+        .cartesian_product(0..count)
+        .filter(|&(giver, recipient)| required.iter().any(|&(g, r)| (giver == g) != (recipient == r)))
+        .collect();
+
+    let min_cycle_length = min_cycle_length(count, constraints);
+    Ok(relaxations(&allowed, &kept, min_cycle_length, limit, control, rng)?
+        .into_iter()
+        .map(|pairs| pairs.into_iter().map(|(giver, recipient)| (participants[giver], participants[recipient])).collect())
+        .collect())
+}
+
+/// Repairs `assignment` after `dropout` leaves, changing as few pairs as possible, using the given random number
+/// generator.
+///
+/// Everyone else keeps their recipient whenever the constraints allow it, so usually only the giver of `dropout`
+/// changes, now giving to the recipient of `dropout`. Costs are not taken into account, and requirements
+/// involving `dropout` have to be removed first.
+///
+/// When that direct reconnection breaks a rule, a search for the fewest changes runs within the limits of `control`,
+/// and returns the repair changing the fewest pairs found so far if it gets stopped.
+pub(crate) fn repair_after_dropout<'a, T, R>(
+    assignment: &Assignment<'a, T>,
+    dropout: &'a T,
+    constraints: &Constraints<'a, T>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    if !assignment.contains_key(dropout) {
+        return Err(DrawError::UnknownParticipant(dropout));
+    }
+
+    let participants = canonical_order(assignment.keys().copied().filter(|&participant| participant != dropout));
+
+    // The giver of `dropout` has to change anyway, so taking over the recipient of `dropout` is the fewest changes
+    let reconnected: Assignment<'a, T> = assignment
+        .iter()
+        .filter(|&(&giver, _)| giver != dropout)
+        .map(|(&giver, &recipient)| (giver, if recipient == dropout { assignment[dropout] } else { recipient }))
+        .collect();
+    if verify_assignment(participants.iter().copied(), constraints, &reconnected).is_ok() {
+        return Ok(reconnected);
+    }
+
+    let index: HashMap<&'a T, usize> = participants.iter().enumerate().map(|(i, &participant)| (participant, i)).collect();
+    let allowed = restricted_recipients(&participants, constraints)?;
+
+    // Keeping a pair saves a change, so the cheapest assignment changes the fewest pairs
+    let mut costs = Costs::default();
+    for (giver, recipient) in assignment {
+        if let (Some(&giver), Some(&recipient)) = (index.get(giver), index.get(recipient)) {
+            costs.add(giver, recipient, -1.0);
+        }
+    }
+
+    let recipient_of = solve_with(&participants, constraints, allowed, Some(&costs), control, rng)?;
+    Ok(to_assignment(&participants, recipient_of))
+}
+
+/// Adds `joiner` to `assignment` after the draw, changing as few existing pairs as possible, using the given
+/// random number generator.
+///
+/// The joiner is spliced between a giver and their recipient, so that only that giver has to be told their new
+/// recipient; among the pairs where the constraints allow it, one of the cheapest is chosen. When gifts may form
+/// several cycles and no such pair exists, two givers swap recipients around the joiner instead. If neither works,
+/// [`DrawError::NoRoomForJoiner`] tells that only a new draw may include the joiner.
+pub(crate) fn add_late_joiner<'a, T, R>(
+    assignment: &Assignment<'a, T>,
+    joiner: &'a T,
+    constraints: &Constraints<'a, T>,
+    rng: &mut R,
+) -> Result<LateJoin<'a, T>, DrawError<'a, T>>
+where
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    if assignment.contains_key(joiner) {
+        return Err(DrawError::AlreadyParticipating(joiner));
+    }
+
+    let participants = canonical_order(assignment.keys().copied().chain([joiner]));
+    let index: HashMap<&'a T, usize> = participants.iter().enumerate().map(|(i, &participant)| (participant, i)).collect();
+    let joiner = index[joiner];
+    let allowed = restricted_recipients(&participants, constraints)?;
+    let costs = pair_costs(&participants, constraints).unwrap_or_default();
+    let single_cycle = constraints.mode == DrawMode::SingleCycle;
+    let rules = Rules::new(&allowed, single_cycle, constraints.min_cycle_length);
+
+    // The joiner gives to themselves until spliced in
+    let mut recipient_of: Vec<usize> = participants
+        .iter()
+        .map(|&participant| assignment.get(participant).map_or(joiner, |recipient| index[recipient]))
+        .collect();
+    let mut givers: Vec<usize> = (0..participants.len()).filter(|&giver| giver != joiner).collect();
+    givers.shuffle(rng);
+
+    // Between `giver` and their recipient
+    let added_cost = |giver: usize| {
+        let recipient = recipient_of[giver];
+        costs.get(giver, joiner) + costs.get(joiner, recipient) - costs.get(giver, recipient)
+    };
+    let spliced = givers
+        .iter()
+        .copied()
+        .filter(|&giver| rules.can_give[giver].contains(joiner) && rules.can_give[joiner].contains(recipient_of[giver]))
+        .min_by(|&a, &b| added_cost(a).total_cmp(&added_cost(b)));
+    if let Some(giver) = spliced {
+        recipient_of[joiner] = recipient_of[giver];
+        recipient_of[giver] = joiner;
+        return Ok(LateJoin { assignment: to_assignment(&participants, recipient_of), renotified: vec![participants[giver]] });
+    }
+
+    // `first` gives to the joiner, who gives to the recipient of `second`, who takes over the recipient of `first`,
+    // which splits a cycle in two or merges two cycles, so cycle lengths need checking
+    if !single_cycle {
+        for (&first, &second) in givers.iter().cartesian_product(&givers) {
+            if first == second
+                || !rules.can_give[first].contains(joiner)
+                || !rules.can_give[joiner].contains(recipient_of[second])
+                || !rules.can_give[second].contains(recipient_of[first])
+            {
+                continue;
+            }
+            let mut candidate = recipient_of.clone();
+            candidate[joiner] = recipient_of[second];
+            candidate[second] = recipient_of[first];
+            candidate[first] = joiner;
+            if rules.is_valid(&candidate) {
+                let renotified = vec![participants[first], participants[second]];
+                return Ok(LateJoin { assignment: to_assignment(&participants, candidate), renotified });
+            }
+        }
+    }
+
+    Err(DrawError::NoRoomForJoiner(participants[joiner]))
+}
+
+/// Generates a Secret Santa pairing with `solver`, using the given random number generator, within the limits
+/// of `control`.
+///
+/// Participants are put in a canonical order before drawing, like [`generate_secret_santa_with_rng`].
+pub(crate) fn generate_secret_santa_with_solver<'a, C, T>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    solver: &dyn DrawSolver<T>,
+    control: &mut SearchControl,
+    rng: &mut dyn RngCore,
+) -> Result<Assignment<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+{
+    let participants = canonical_order(participants);
+    solver.solve(&participants, constraints, control, rng)
+}
+
+/// The steps `control` recorded while drawing among `participants`, with participants named for replay.
+pub(crate) fn search_trace<'a, C, T>(participants: C, control: &SearchControl) -> Trace
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Display + Ord + 'a,
+{
+    let (events, truncated) = control.events();
+    Trace {
+        // Searches refer to participants by their index in canonical order
+        participants: canonical_order(participants).iter().map(|participant| participant.to_string()).collect(),
+        events: events.to_vec(),
+        truncated,
+    }
+}
+
+/// Checks that `assignment` follows `constraints` among `participants`, reporting every way it doesn't.
+///
+/// Everyone must give one gift and receive one, never to themselves, respecting exclusions, history excluded
+/// by [`HistoryPolicy::Exclude`], required pairings and cycle rules. This doesn't depend on how the assignment
+/// was made, so it also checks draws from older versions, from a file, or from a hat.
+pub(crate) fn verify_assignment<'a, C, T>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    assignment: &Assignment<'a, T>,
+) -> Result<(), Vec<Violation<'a, T>>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+{
+    let participants = canonical_order(participants);
+    let known: HashSet<&'a T> = participants.iter().copied().collect();
+    let pairs: Vec<(&'a T, &'a T)> = assignment.iter().map(|(&giver, &recipient)| (giver, recipient)).sorted().collect();
+    let mut violations = Vec::new();
+
+    let mut excluded_by_history = HashSet::new();
+    if let Some((history, HistoryPolicy::Exclude { years })) = &constraints.history {
+        excluded_by_history.extend(history.pairs().filter(|&(age, _, _)| age <= *years).map(|(_, giver, recipient)| (giver, recipient)));
+    }
+
+    let mut givers_of: HashMap<&'a T, Vec<&'a T>> = HashMap::new();
+    for &(giver, recipient) in &pairs {
+        if !known.contains(giver) {
+            violations.push(Violation::UnknownGiver(giver));
+        }
+        if !known.contains(recipient) {
+            violations.push(Violation::UnknownRecipient(recipient));
+        }
+        if giver == recipient {
+            violations.push(Violation::SelfGift(giver));
+        }
+        if constraints.exclusions.get(giver).is_some_and(|excluded| excluded.contains(recipient)) {
+            violations.push(Violation::Excluded { giver, recipient });
+        }
+        if excluded_by_history.contains(&(giver, recipient)) {
+            violations.push(Violation::RepeatsHistory { giver, recipient });
+        }
+        givers_of.entry(recipient).or_default().push(giver);
+    }
+
+    for &participant in &participants {
+        if !assignment.contains_key(participant) {
+            violations.push(Violation::GivesNothing(participant));
+        }
+        match givers_of.get(participant) {
+            None => violations.push(Violation::ReceivesNothing(participant)),
+            Some(givers) if givers.len() > 1 => {
+                violations.push(Violation::ReceivesSeveral { recipient: participant, givers: givers.clone() })
+            }
+            Some(_) => {}
+        }
+    }
+
+    for &(giver, recipient) in &constraints.required {
+        if assignment.get(giver) != Some(&recipient) {
+            violations.push(Violation::MissingRequiredPair { giver, recipient });
+        }
+    }
+
+    // Gift cycles only exist once everyone gives to and receives from exactly one participant
+    let is_permutation = pairs.len() == participants.len()
+        && pairs.iter().all(|(giver, recipient)| known.contains(giver) && known.contains(recipient) && givers_of[recipient].len() == 1);
+    if is_permutation {
+        let min_cycle_length = min_cycle_length(participants.len(), constraints);
+        let mut seen = HashSet::new();
+        for &start in &participants {
+            let mut cycle = Vec::new();
+            let mut current = start;
+            while seen.insert(current) {
+                cycle.push(current);
+                current = assignment[current];
+            }
+            // Self-gifts are already reported
+            if cycle.len() > 1 && cycle.len() < min_cycle_length {
+                violations.push(Violation::CycleTooShort(cycle));
+            }
+        }
+    }
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(violations),
+    }
+}
+
+/// Draws a single open chain through every participant, starting from `starter` if given, using the given
+/// random number generator, within the limits of `control`.
+///
+/// Each participant gives to the next one in the chain, respecting exclusions, history, required pairings and
+/// costs, while cycle rules don't apply. The last participant gives to no one unless the event closes the chain,
+/// which [`Chain::closes`] tells whether the constraints allow.
+pub(crate) fn generate_secret_santa_chain<'a, C, T, R>(
+    participants: C,
+    constraints: &Constraints<'a, T>,
+    starter: Option<&'a T>,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Chain<'a, T>, DrawError<'a, T>>
+where
+    C: IntoIterator<Item = &'a T>,
+    T: Eq + Hash + Ord,
+    R: Rng + ?Sized,
+{
+    let participants = canonical_order(participants);
+    let count = participants.len();
+    if count < 2 {
+        return Err(DrawError::TooFewParticipants { count, needed: 2 });
+    }
+    let starter = match starter {
+        Some(starter) => Some(participants.binary_search(&starter).map_err(|_| DrawError::UnknownParticipant(starter))?),
+        None => None,
+    };
+    let mut restricted = allowed_recipients(&participants, constraints);
+    let required = requirements(&participants, &restricted, constraints, 1)?;
+    apply_requirements(&mut restricted, &required);
+
+    // An extra participant after the last one and before the starter turns the chain into a single cycle. A required
+    // pair has to be passed along the chain, so its giver can't be last nor its recipient first
+    let end = count;
+    let mut allowed: Vec<BitSet> = restricted
+        .iter()
+        .enumerate()
+        .map(|(giver, recipients)| {
+            let mut extended = BitSet::new(count + 1);
+            for recipient in recipients.iter() {
+                extended.insert(recipient);
+            }
+            if !required.iter().any(|&(required_giver, _)| required_giver == giver) {
+                extended.insert(end);
+            }
+            extended
+        })
+        .collect();
+    let mut starters = BitSet::new(count + 1);
+    match starter {
+        Some(starter) => starters.insert(starter),
+        None => (0..count).for_each(|participant| starters.insert(participant)),
+    }
+    for &(_, recipient) in &required {
+        starters.remove(recipient);
+    }
+    allowed.push(starters);
+
+    let costs = pair_costs(&participants, constraints);
+    let recipient_of = find_cycles(&allowed, count + 1, costs.as_ref(), control, rng)
+        .map_err(DrawError::Interrupted)?
+        .ok_or(DrawError::SearchExhausted)?;
+
+    let mut order = Vec::with_capacity(count);
+    let mut current = recipient_of[end];
+    while current != end {
+        order.push(current);
+        current = recipient_of[current];
+    }
+    let closes = allowed[order[count - 1]].contains(order[0]);
+    Ok(Chain { order: order.into_iter().map(|participant| participants[participant]).collect(), closes })
+}
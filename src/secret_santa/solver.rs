@@ -0,0 +1,495 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use rayon::prelude::*;
+use std::hash::Hash;
+use std::sync::Mutex;
+use crate::secret_santa::constraints::{Constraints, DrawMode};
+use crate::secret_santa::control::{CancelToken, Interruption, Progress, SearchControl, SplitRecord};
+use crate::secret_santa::draw_error::DrawError;
+use crate::secret_santa::bitset::BitSet;
+use crate::secret_santa::optimize::Costs;
+use crate::secret_santa::sampling::{Rules, SamplingMethod};
+use crate::secret_santa::sat::{Lit, Sat};
+use crate::secret_santa::secret_santa::{
+    check_feasibility, generate_secret_santa_with_control, min_cycle_length, pair_costs, random_matching,
+    restricted_recipients, sample_secret_santa_with_rng, to_assignment, Assignment,
+};
+use std::collections::HashMap;
+
+/// A way of drawing Secret Santa pairings, so that algorithms can be swapped and compared.
+pub trait DrawSolver<T> {
+    /// A short name to pick the solver by.
+    fn name(&self) -> &'static str;
+
+    /// Draws the recipient of every participant of `participants`, which are in canonical order, following `constraints`,
+    /// within the limits of `control`.
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>>;
+}
+
+/// The exhaustive search behind `generate_secret_santa`, which finds a pairing whenever one exists
+/// and takes costs into account.
+pub struct Backtracking;
+
+impl<T: Eq + Hash + Ord> DrawSolver<T> for Backtracking {
+    fn name(&self) -> &'static str {
+        "backtracking"
+    }
+
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>> {
+        generate_secret_santa_with_control(participants.iter().copied(), constraints, control, rng)
+    }
+}
+
+/// Draws a random perfect matching of givers to recipients, then merges its gift cycles until they follow
+/// the cycle rules, starting over with another matching up to `restarts` times.
+///
+/// Much faster than an exhaustive search when constraints are loose, but it may give up on tight constraints
+/// even though a pairing exists. Costs are not taken into account.
+pub struct CycleMerging {
+    pub restarts: usize,
+}
+
+impl<T: Eq + Hash + Ord> DrawSolver<T> for CycleMerging {
+    fn name(&self) -> &'static str {
+        "cycle-merging"
+    }
+
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>> {
+        let allowed = restricted_recipients(participants, constraints)?;
+        check_feasibility(participants, &allowed)?;
+        let rules = Rules::new(&allowed, constraints.mode == DrawMode::SingleCycle, constraints.min_cycle_length);
+
+        let restarts = self.restarts.max(1) as u64;
+        for restart in 0..restarts {
+            let progress = Progress { nodes: restart + 1, backtracks: restart, depth: 0, max_depth: participants.len() };
+            control.check(progress).map_err(DrawError::Interrupted)?;
+            if let Some(recipient_of) = merge_cycles(&allowed, &rules, rng) {
+                return Ok(to_assignment(participants, recipient_of));
+            }
+        }
+        Err(DrawError::SearchExhausted)
+    }
+}
+
+/// Labels the gift cycle of each participant, returning the labels and the length of each cycle.
+fn label_cycles(recipient_of: &[usize]) -> (Vec<usize>, Vec<usize>) {
+    let mut cycle_of = vec![usize::MAX; recipient_of.len()];
+    let mut lengths = Vec::new();
+    for start in 0..recipient_of.len() {
+        let mut length = 0;
+        let mut current = start;
+        while cycle_of[current] == usize::MAX {
+            cycle_of[current] = lengths.len();
+            current = recipient_of[current];
+            length += 1;
+        }
+        if length > 0 {
+            lengths.push(length);
+        }
+    }
+    (cycle_of, lengths)
+}
+
+/// Draws a random perfect matching among `allowed`, then merges every cycle shorter than `rules` allow into
+/// another one, by swapping the recipients of a giver in each. Returns `None` if a cycle can't be merged.
+fn merge_cycles<R: Rng + ?Sized>(allowed: &[BitSet], rules: &Rules, rng: &mut R) -> Option<Vec<usize>> {
+    let mut recipient_of = random_matching(allowed, None, rng)?;
+    let mut participants: Vec<usize> = (0..allowed.len()).collect();
+
+    loop {
+        let (cycle_of, lengths) = label_cycles(&recipient_of);
+        let short = (0..lengths.len())
+            .filter(|&cycle| lengths[cycle] < rules.min_cycle_length)
+            .min_by_key(|&cycle| lengths[cycle]);
+        let Some(short) = short else { return Some(recipient_of) };
+
+        // Swapping the recipients of givers in two different cycles joins them into one
+        participants.shuffle(rng);
+        let inside = participants.iter().copied().filter(|&p| cycle_of[p] == short);
+        let (first, second) = inside
+            .flat_map(|first| participants.iter().map(move |&second| (first, second)))
+            .find(|&(first, second)| {
+                cycle_of[second] != short
+                    && rules.can_give[first].contains(recipient_of[second])
+                    && rules.can_give[second].contains(recipient_of[first])
+            })?;
+        recipient_of.swap(first, second);
+    }
+}
+
+/// Picks uniformly among all valid pairings with a sampling method suited to the number of participants.
+/// Costs are not taken into account. Search limits apply to every attempt or step, and to the search for a
+/// starting pairing.
+pub struct UniformSampling;
+
+impl<T: Eq + Hash + Ord> DrawSolver<T> for UniformSampling {
+    fn name(&self) -> &'static str {
+        "uniform-sampling"
+    }
+
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>> {
+        let method = SamplingMethod::for_participants(participants.len());
+        sample_secret_santa_with_rng(participants.iter().copied(), constraints, method, control, rng)
+    }
+}
+
+/// Encodes the draw as a boolean satisfiability problem, with one variable per allowed pair, and solves it
+/// with the built-in SAT solver.
+///
+/// Everyone gives and receives exactly one gift. Cycle rules are added lazily: whenever a solution has a cycle
+/// that is too short, a clause requiring some gift to leave its participants rules it out, and solving resumes
+/// with everything learned so far. With costs, the total cost of the draw is as low as possible: a limit on the
+/// total weight of the chosen pairs keeps requiring a cheaper draw than the last one found, until there is none.
+pub struct SatSolving;
+
+impl<T: Eq + Hash + Ord> DrawSolver<T> for SatSolving {
+    fn name(&self) -> &'static str {
+        "sat"
+    }
+
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>> {
+        let allowed = control.time_phase("constraints", |_| restricted_recipients(participants, constraints))?;
+        control.time_phase("feasibility", |_| check_feasibility(participants, &allowed))?;
+        let min_cycle_length = min_cycle_length(participants.len(), constraints);
+
+        let recipient_of = control.time_phase("search", |control| match pair_costs(participants, constraints) {
+            Some(costs) => sat_cheapest_cycles(&allowed, min_cycle_length, &costs, control, rng),
+            None => sat_cycles(&allowed, min_cycle_length, control, rng),
+        });
+        let recipient_of = recipient_of.map_err(DrawError::Interrupted)?;
+        Ok(to_assignment(participants, recipient_of.ok_or(DrawError::SearchExhausted)?))
+    }
+}
+
+/// Finds gift cycles going through every participant, each at least `min_cycle_length` participants long,
+/// where `allowed[giver]` is the set of recipients `giver` may give to, by solving a satisfiability problem.
+fn sat_cycles<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    min_cycle_length: usize,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<usize>>, Interruption> {
+    Ok(sat_rounds(allowed, min_cycle_length, 1, &[], control, rng)?.map(|mut rounds| rounds.remove(0)))
+}
+
+/// Finds `rounds` assignments that never repeat a pair, each made of gift cycles like [`sat_cycles`], all at once.
+///
+/// Solving every round together means an early round never takes pairs that later rounds can't do without.
+/// Every `(giver, recipient)` pair of `required` is part of the first round, and must be allowed.
+pub fn sat_rounds<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    min_cycle_length: usize,
+    rounds: usize,
+    required: &[(usize, usize)],
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<Vec<usize>>>, Interruption> {
+    let mut sat = Sat::default();
+    let gives: Vec<Vec<Vec<(usize, Lit)>>> = (0..rounds).map(|_| encode_round(&mut sat, allowed, min_cycle_length, rng)).collect();
+    for &(giver, recipient) in required {
+        if let Some(&(_, lit)) = gives.first().and_then(|round| round[giver].iter().find(|&&(r, _)| r == recipient)) {
+            sat.add_clause(&[lit]);
+        }
+    }
+
+    // Each pair gives a gift in one round at most
+    if rounds > 1 {
+        for giver in 0..allowed.len() {
+            for index in 0..gives[0][giver].len() {
+                sat.add_at_most_one(&gives.iter().map(|round| round[giver][index].1).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    solve_rounds(&mut sat, &gives, min_cycle_length, control)
+}
+
+/// Solves `sat` until the assignment of every round of `gives`, as encoded by [`encode_round`], has no cycle
+/// shorter than `min_cycle_length`, ruling out short cycles as they come up.
+fn solve_rounds(
+    sat: &mut Sat,
+    gives: &[Vec<Vec<(usize, Lit)>>],
+    min_cycle_length: usize,
+    control: &mut SearchControl,
+) -> Result<Option<Vec<Vec<usize>>>, Interruption> {
+    loop {
+        let Some(model) = sat.solve(control)? else { return Ok(None) };
+        let mut assignments = Vec::with_capacity(gives.len());
+        let mut valid = true;
+        for round in gives {
+            let recipient_of: Option<Vec<usize>> = round
+                .iter()
+                .map(|recipients| recipients.iter().find(|(_, lit)| model[lit.var()]).map(|&(recipient, _)| recipient))
+                .collect();
+            let Some(recipient_of) = recipient_of else { return Ok(None) };
+            valid &= !cut_short_cycles(sat, round, &recipient_of, min_cycle_length);
+            assignments.push(recipient_of);
+        }
+        if valid {
+            return Ok(Some(assignments));
+        }
+    }
+}
+
+/// Adds the variables and clauses of one assignment to `sat`, returning the variable of each allowed pair,
+/// listed per giver in increasing order of recipients.
+fn encode_round<R: Rng + ?Sized>(sat: &mut Sat, allowed: &[BitSet], min_cycle_length: usize, rng: &mut R) -> Vec<Vec<(usize, Lit)>> {
+    let count = allowed.len();
+    let gives: Vec<Vec<(usize, Lit)>> = allowed
+        .iter()
+        .map(|recipients| recipients.iter().map(|recipient| (recipient, Lit::new(sat.new_var(false), true))).collect())
+        .collect();
+    let ordered = || gives.iter().enumerate().flat_map(|(giver, recipients)| recipients.iter().map(move |&(r, lit)| (giver, r, lit)));
+    let pairs: HashMap<(usize, usize), Lit> = ordered().map(|(giver, recipient, lit)| ((giver, recipient), lit)).collect();
+
+    // Random initial activities make solutions vary, until conflicts take over
+    for (_, _, lit) in ordered() {
+        sat.bump(lit.var(), rng.gen());
+    }
+
+    let mut received: Vec<Vec<Lit>> = vec![Vec::new(); count];
+    for (_, recipient, lit) in ordered() {
+        received[recipient].push(lit);
+    }
+    for recipients in &gives {
+        sat.add_exactly_one(&recipients.iter().map(|&(_, lit)| lit).collect::<Vec<_>>());
+    }
+    for givers in &received {
+        sat.add_exactly_one(givers);
+    }
+    if min_cycle_length > 2 {
+        for (giver, recipient, lit) in ordered() {
+            if let Some(&back) = pairs.get(&(recipient, giver)) {
+                sat.add_clause(&[!lit, !back]);
+            }
+        }
+    }
+    gives
+}
+
+/// Rules out every cycle of `recipient_of` shorter than `min_cycle_length` with a clause on the variables of
+/// `gives`, returning whether there was any.
+fn cut_short_cycles(sat: &mut Sat, gives: &[Vec<(usize, Lit)>], recipient_of: &[usize], min_cycle_length: usize) -> bool {
+    let (cycle_of, lengths) = label_cycles(recipient_of);
+    let short: Vec<usize> = (0..lengths.len()).filter(|&cycle| lengths[cycle] < min_cycle_length).collect();
+
+    // Fewer participants than a cycle needs can't only give to each other
+    for &cycle in &short {
+        let leaving: Vec<Lit> = gives
+            .iter()
+            .enumerate()
+            .filter(|&(giver, _)| cycle_of[giver] == cycle)
+            .flat_map(|(_, recipients)| recipients.iter().filter(|&&(r, _)| cycle_of[r] != cycle).map(|&(_, lit)| lit))
+            .collect();
+        sat.add_clause(&leaving);
+    }
+    !short.is_empty()
+}
+
+/// Units of SAT weight per unit of cost, so that halving history penalties stay exact for twenty years.
+const COST_RESOLUTION: f64 = (1u64 << 20) as f64;
+
+/// Like [`sat_cycles`], with the lowest total cost. Every solution found bounds the total cost of the next one
+/// below its own, until there is no cheaper one. If `control` runs out of time or nodes after a first solution,
+/// the cheapest cycles found so far are returned.
+///
+/// Costs are rounded to a millionth or so, so pairings within that much of the cheapest total may be returned.
+fn sat_cheapest_cycles<R: Rng + ?Sized>(
+    allowed: &[BitSet],
+    min_cycle_length: usize,
+    costs: &Costs,
+    control: &mut SearchControl,
+    rng: &mut R,
+) -> Result<Option<Vec<usize>>, Interruption> {
+    let mut sat = Sat::default();
+    let gives = [encode_round(&mut sat, allowed, min_cycle_length, rng)];
+
+    // Everyone gives exactly one gift, so only what it costs more than their cheapest allowed gift counts
+    let weights: Vec<Vec<u64>> = gives[0]
+        .iter()
+        .enumerate()
+        .map(|(giver, recipients)| {
+            let cheapest = recipients.iter().map(|&(r, _)| costs.get(giver, r)).fold(f64::INFINITY, f64::min);
+            recipients.iter().map(|&(r, _)| ((costs.get(giver, r) - cheapest) * COST_RESOLUTION).round() as u64).collect()
+        })
+        .collect();
+    let terms: Vec<(Lit, u64)> = gives[0]
+        .iter()
+        .zip(&weights)
+        .flat_map(|(recipients, weights)| recipients.iter().zip(weights).map(|(&(_, lit), &weight)| (lit, weight)))
+        .collect();
+    let limit = sat.add_at_most_weight(&terms, u64::MAX);
+
+    let mut best = None;
+    loop {
+        match solve_rounds(&mut sat, &gives, min_cycle_length, control) {
+            Ok(Some(mut rounds)) => {
+                let recipient_of = rounds.remove(0);
+                let weight: u64 = gives[0]
+                    .iter()
+                    .zip(&weights)
+                    .zip(&recipient_of)
+                    .map(|((recipients, weights), &recipient)| {
+                        let index = recipients.iter().position(|&(r, _)| r == recipient).unwrap_or_default();
+                        weights[index]
+                    })
+                    .sum();
+                best = Some(recipient_of);
+                match weight.checked_sub(1) {
+                    Some(bound) => sat.tighten_weight_limit(limit, bound),
+                    None => break,
+                }
+            }
+            Ok(None) => break,
+            Err(Interruption::Cancelled) => return Err(Interruption::Cancelled),
+            Err(interruption) if best.is_none() => return Err(interruption),
+            Err(_) => break,
+        }
+    }
+    Ok(best)
+}
+
+/// Nodes each search of a [`Portfolio`] may try in the first round, doubled every round.
+const PORTFOLIO_INITIAL_BUDGET: u64 = 10_000;
+
+/// Searches of the default [`Portfolio`], fixed rather than one per core so that a seed gives the same pairing
+/// on every machine. The number of cores only changes how many of them run at once.
+const PORTFOLIO_SEARCHES: usize = 8;
+
+/// Runs `searches` randomized backtracking searches side by side on every CPU core, in rounds with a node
+/// budget that doubles every round, and keeps the first search to succeed.
+///
+/// Randomized backtracking sometimes gets lost for a long time where another order of recipients succeeds
+/// quickly; restarting searches with fresh seeds avoids waiting on the unlucky ones. Every search is seeded from
+/// the given random number generator, and among the searches succeeding in a round the one started first wins,
+/// so with as many searches, the same seed always yields the same pairing however the searches are scheduled.
+/// With costs, the winner returns the cheapest pairing it found within its budget. The nodes of every search count
+/// towards the node limit of the draw, and no search is given more nodes than are left. The phase times and trace
+/// of the search whose result is returned are added to the draw's, as they explain it.
+pub struct Portfolio {
+    pub searches: usize,
+}
+
+impl<T: Eq + Hash + Ord + Sync> DrawSolver<T> for Portfolio {
+    fn name(&self) -> &'static str {
+        "portfolio"
+    }
+
+    fn solve<'a>(
+        &self,
+        participants: &[&'a T],
+        constraints: &Constraints<'a, T>,
+        control: &mut SearchControl,
+        rng: &mut dyn RngCore,
+    ) -> Result<Assignment<'a, T>, DrawError<'a, T>> {
+        let allowed = restricted_recipients(participants, constraints)?;
+        check_feasibility(participants, &allowed)?;
+
+        let mut budget = PORTFOLIO_INITIAL_BUDGET;
+        // The first search of the last round, which explains running out of nodes
+        let mut unfinished = None;
+        loop {
+            let stopped = match control.nodes_left() {
+                Some(0) => Err(Interruption::OutOfNodes),
+                _ => control.check_stopped(),
+            };
+            if let Err(interruption) = stopped {
+                if let Some(record) = unfinished {
+                    control.adopt_split(record);
+                }
+                return Err(DrawError::Interrupted(interruption));
+            }
+            let nodes = control.nodes_left().map_or(budget, |left| budget.min(left));
+
+            let seeds: Vec<u64> = (0..self.searches.max(1)).map(|_| rng.next_u64()).collect();
+            let tokens: Vec<CancelToken> = seeds.iter().map(|_| CancelToken::default()).collect();
+            let shared = Mutex::new(&mut *control);
+            let results: Vec<_> = seeds
+                .par_iter()
+                .enumerate()
+                .map(|(index, &seed)| {
+                    let split = shared.lock().unwrap().split();
+                    let mut search = split
+                        .node_limit(nodes)
+                        .cancel_token(tokens[index].clone())
+                        .on_progress(|progress| shared.lock().unwrap().report(progress));
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    let result = generate_secret_santa_with_control(participants.iter().copied(), constraints, &mut search, &mut rng);
+
+                    match &result {
+                        // Later searches can't win anymore, and none can once a search has ruled everything out
+                        Ok(_) => tokens[index + 1..].iter().for_each(CancelToken::cancel),
+                        Err(DrawError::SearchExhausted) => tokens.iter().for_each(CancelToken::cancel),
+                        Err(_) => {}
+                    }
+                    (result, search.split_record())
+                })
+                .collect();
+            let (mut results, mut records): (Vec<_>, Vec<SplitRecord>) = results.into_iter().unzip();
+            for record in &records {
+                control.add_split_stats(record);
+            }
+
+            // Only searches out of budget are worth another round, others were stopped from outside
+            let out_of_nodes = Err(DrawError::Interrupted(Interruption::OutOfNodes));
+            let kept = results
+                .iter()
+                .position(Result::is_ok)
+                .or_else(|| results.iter().position(|result| *result == Err(DrawError::SearchExhausted)))
+                .or_else(|| results.iter().position(|result| *result != out_of_nodes));
+            if let Some(kept) = kept {
+                control.adopt_split(records.swap_remove(kept));
+                return results.swap_remove(kept);
+            }
+            unfinished = Some(records.swap_remove(0));
+            budget *= 2;
+        }
+    }
+}
+
+/// Every solver with its default settings, e.g. to offer a choice between them.
+pub fn solvers<T: Eq + Hash + Ord + Sync>() -> Vec<Box<dyn DrawSolver<T>>> {
+    vec![
+        Box::new(Backtracking),
+        Box::new(CycleMerging { restarts: 20 }),
+        Box::new(UniformSampling),
+        Box::new(SatSolving),
+        Box::new(Portfolio { searches: PORTFOLIO_SEARCHES }),
+    ]
+}
+
+/// The solver called `name`, with its default settings.
+pub fn solver_named<T: Eq + Hash + Ord + Sync>(name: &str) -> Option<Box<dyn DrawSolver<T>>> {
+    solvers().into_iter().find(|solver| solver.name() == name)
+}
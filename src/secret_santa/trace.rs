@@ -0,0 +1,96 @@
+use std::io::{self, BufRead, Write};
+
+/// One step of a backtracking search, with participants given as indices into the trace's participants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The search has this many ways of continuing from the current partial assignment.
+    Choose { options: usize },
+    /// The participant joins the partial assignment, receiving from the one before, or starting a new cycle.
+    Push { participant: usize, new_cycle: bool },
+    /// The participant leaves the partial assignment, as the search backtracks.
+    Pop { participant: usize },
+}
+
+/// The steps a search went through, to be written to a file and replayed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// Names of the participants, in the order events refer to them.
+    pub participants: Vec<String>,
+    pub events: Vec<TraceEvent>,
+    /// Whether events were dropped after the recording limit was reached.
+    pub truncated: bool,
+}
+
+impl Trace {
+    /// Writes the trace as text, one participant or event per line.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        for name in &self.participants {
+            writeln!(writer, "participant {}", name)?;
+        }
+        for event in &self.events {
+            match event {
+                TraceEvent::Choose { options } => writeln!(writer, "choose {}", options)?,
+                TraceEvent::Push { participant, new_cycle: false } => writeln!(writer, "push {}", participant)?,
+                TraceEvent::Push { participant, new_cycle: true } => writeln!(writer, "push {} cycle", participant)?,
+                TraceEvent::Pop { participant } => writeln!(writer, "pop {}", participant)?,
+            }
+        }
+        if self.truncated {
+            writeln!(writer, "truncated")?;
+        }
+        Ok(())
+    }
+
+    /// Reads a trace written by [`Trace::write_to`].
+    pub fn read_from(reader: impl BufRead) -> io::Result<Self> {
+        let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid trace line: {}", line));
+        let mut trace = Trace::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(name) = line.strip_prefix("participant ") {
+                trace.participants.push(name.to_string());
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let index = |position: usize| -> io::Result<usize> {
+                let index: usize = words.get(position).and_then(|word| word.parse().ok()).ok_or_else(|| invalid(&line))?;
+                match index < trace.participants.len() {
+                    true => Ok(index),
+                    false => Err(invalid(&line)),
+                }
+            };
+            let event = match words.as_slice() {
+                ["choose", options] => TraceEvent::Choose { options: options.parse().map_err(|_| invalid(&line))? },
+                ["push", _] => TraceEvent::Push { participant: index(1)?, new_cycle: false },
+                ["push", _, "cycle"] => TraceEvent::Push { participant: index(1)?, new_cycle: true },
+                ["pop", _] => TraceEvent::Pop { participant: index(1)? },
+                ["truncated"] => {
+                    trace.truncated = true;
+                    continue;
+                }
+                [] => continue,
+                _ => return Err(invalid(&line)),
+            };
+            trace.events.push(event);
+        }
+        Ok(trace)
+    }
+
+    /// Replays the first `steps` events, returning the partial assignment they lead to: the participants
+    /// in the order they joined, each with whether they started a new cycle.
+    pub fn path_at(&self, steps: usize) -> Vec<(usize, bool)> {
+        let mut path = Vec::new();
+        for event in self.events.iter().take(steps) {
+            match *event {
+                TraceEvent::Choose { .. } => {}
+                TraceEvent::Push { participant, new_cycle } => path.push((participant, new_cycle)),
+                TraceEvent::Pop { .. } => {
+                    path.pop();
+                }
+            }
+        }
+        path
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt::{Display, Formatter};
+use itertools::Itertools;
+
+/// A way an assignment breaks the rules of a draw.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation<'a, T> {
+    /// Someone who isn't a participant gives a gift.
+    UnknownGiver(&'a T),
+    /// Someone who isn't a participant receives a gift.
+    UnknownRecipient(&'a T),
+    /// The participant gives no gift.
+    GivesNothing(&'a T),
+    /// The participant receives no gift.
+    ReceivesNothing(&'a T),
+    /// The participant receives a gift from each of these givers.
+    ReceivesSeveral { recipient: &'a T, givers: Vec<&'a T> },
+    /// The participant gives to themselves.
+    SelfGift(&'a T),
+    /// A giver gives to a recipient they are excluded from.
+    Excluded { giver: &'a T, recipient: &'a T },
+    /// A giver gives to a recipient they gave to in a previous year that history excludes.
+    RepeatsHistory { giver: &'a T, recipient: &'a T },
+    /// A giver doesn't give to the recipient they are required to give to.
+    MissingRequiredPair { giver: &'a T, recipient: &'a T },
+    /// Gifts go around a cycle through these participants, shorter than cycle rules allow.
+    CycleTooShort(Vec<&'a T>),
+}
+
+impl<T: Display> Display for Violation<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::UnknownGiver(giver) => write!(f, "{} gives a gift, but is not a participant", giver),
+            Violation::UnknownRecipient(recipient) => write!(f, "{} receives a gift, but is not a participant", recipient),
+            Violation::GivesNothing(giver) => write!(f, "{} gives no gift", giver),
+            Violation::ReceivesNothing(recipient) => write!(f, "{} receives no gift", recipient),
+            Violation::ReceivesSeveral { recipient, givers } => {
+                write!(f, "{} receives a gift from each of {}", recipient, givers.iter().join(", "))
+            }
+            Violation::SelfGift(participant) => write!(f, "{} gives to themselves", participant),
+            Violation::Excluded { giver, recipient } => {
+                write!(f, "{} gives to {}, but is excluded from it", giver, recipient)
+            }
+            Violation::RepeatsHistory { giver, recipient } => {
+                write!(f, "{} gives to {} again, which history excludes", giver, recipient)
+            }
+            Violation::MissingRequiredPair { giver, recipient } => {
+                write!(f, "{} is required to give to {}, but doesn't", giver, recipient)
+            }
+            Violation::CycleTooShort(cycle) => {
+                write!(f, "gifts go around a cycle that is too short: {}", cycle.iter().join(" -> "))
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
-use itertools::Itertools;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use crate::participant::Participant;
-use crate::secret_santa::generate_secret_santa;
+use crate::secret_santa::constraints::Constraints;
+use crate::secret_santa::control::SearchControl;
+use crate::secret_santa::secret_santa::generate_secret_santa_with_control;
+use std::time::Duration;
 use timing::Timer;
 
 pub fn generate_participants<'a>(number: usize) -> HashSet<Participant> {
@@ -16,15 +19,16 @@ pub fn generate_participants<'a>(number: usize) -> HashSet<Participant> {
     participants
 }
 
-fn generate_large_exclusions<'a, T, C>(
+fn generate_large_exclusions<'a, T, C, R>(
     participants: C,
     exclusion_probability: f64,
+    rng: &mut R,
 ) -> HashMap<&'a T, HashSet<&'a T>>
 where
     C: IntoIterator<Item = &'a T>,
     T: Eq + Hash,
+    R: Rng,
 {
-    let mut rng = rand::thread_rng();
     let mut exclusions: HashMap<&'a T, HashSet<&'a T>> = HashMap::new();
 
     let participants_vec: Vec<_> = participants.into_iter().collect();
@@ -70,27 +74,1079 @@ macro_rules! time_exec {
     };
 }
 pub fn run_test() {
+    let seed = rand::thread_rng().gen();
+    let mut rng = StdRng::seed_from_u64(seed);
+    println!("Seed: {}", seed);
+
     let binding: HashSet<_> = time_exec!("Binding generation", {
         generate_participants(500)
     });
     let participants: Vec<_> = time_exec!("Participant generation", {
         binding.iter().map(|s| s).collect()
     });
-    let mut exclusions = time_exec!("Exclusions generation", {
-        generate_large_exclusions(participants.clone(), 0.9)
+    let exclusions = time_exec!("Exclusions generation", {
+        generate_large_exclusions(participants.clone(), 0.9, &mut rng)
     });
 
+    // Give up after a minute, reporting how the search is going along the way
+    let mut control = SearchControl::default()
+        .time_limit(Duration::from_secs(60))
+        .on_progress(|progress| {
+            println!(
+                "{} nodes, {} backtracks, {}/{} participants placed",
+                progress.nodes, progress.backtracks, progress.depth, progress.max_depth
+            )
+        });
     let results = time_exec!("Paring", {
-        generate_secret_santa(participants, &mut exclusions)
+        generate_secret_santa_with_control(participants, &Constraints::default().exclusions(exclusions), &mut control, &mut rng)
     });
 
     match results {
-        Some(pairings) => {
-            for (giver, receiver) in pairings {
+        Ok(assignment) => {
+            for (giver, receiver) in assignment {
                 println!("{} gives a gift to {}", giver, receiver);
             }
             println!("Valid Secret Santa assignment found.");
         }
-        None => println!("No valid Secret Santa assignment found."),
+        Err(error) => println!("No valid Secret Santa assignment found: {}.", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use crate::secret_santa::constraints::DrawMode;
+    use crate::secret_santa::history::History;
+    use std::ops::RangeInclusive;
+    use crate::secret_santa::secret_santa::{generate_secret_santa_seeded, generate_secret_santa_with_rng, verify_assignment, Assignment};
+
+    /// Lengths of the gift cycles of an assignment of participants `0..n`, given as the recipient of each giver.
+    fn cycle_lengths(recipient_of: &[usize]) -> Vec<usize> {
+        let mut seen = vec![false; recipient_of.len()];
+        let mut lengths = Vec::new();
+        for start in 0..recipient_of.len() {
+            let mut length = 0;
+            let mut current = start;
+            while !seen[current] {
+                seen[current] = true;
+                current = recipient_of[current];
+                length += 1;
+            }
+            if length > 0 {
+                lengths.push(length);
+            }
+        }
+        lengths
+    }
+
+    /// Lists by brute force every valid assignment of participants `0..count`, as the recipient of each giver.
+    fn valid_assignments(count: usize, constraints: &Constraints<usize>) -> Vec<Vec<usize>> {
+        (0..count)
+            .permutations(count)
+            .filter(|recipient_of| {
+                let allowed = recipient_of.iter().enumerate().all(|(giver, recipient)| {
+                    giver != *recipient && !constraints.exclusions.get(&giver).is_some_and(|excluded| excluded.contains(recipient))
+                });
+                let lengths = cycle_lengths(recipient_of);
+                allowed && match constraints.mode {
+                    DrawMode::SingleCycle => lengths.len() == 1,
+                    DrawMode::Derangement => lengths.iter().all(|&length| length >= constraints.min_cycle_length),
+                }
+            })
+            .collect()
+    }
+
+    fn to_recipients(assignment: &Assignment<usize>) -> Vec<usize> {
+        (0..assignment.len()).map(|giver| *assignment[&giver]).collect()
+    }
+
+    /// Calls `check` with participants `0..count` for each of `counts`, under `repeats` random sets of exclusions,
+    /// each in every draw mode and cycle rule worth checking.
+    fn for_each_small_case<R: Rng>(
+        counts: RangeInclusive<usize>,
+        repeats: usize,
+        exclusion_probability: f64,
+        rng: &mut R,
+        mut check: impl FnMut(&[usize], Constraints<usize>, &mut R),
+    ) {
+        for count in counts {
+            let participants: Vec<usize> = (0..count).collect();
+            for _ in 0..repeats {
+                let exclusions = generate_large_exclusions(&participants, exclusion_probability, rng);
+                for (mode, min_cycle_length) in [(DrawMode::SingleCycle, 2), (DrawMode::Derangement, 2), (DrawMode::Derangement, 3)] {
+                    let constraints = Constraints::default()
+                        .exclusions(exclusions.clone())
+                        .mode(mode)
+                        .min_cycle_length(min_cycle_length);
+                    check(&participants, constraints, rng);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn solver_agrees_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for exclusion_probability in [0.2, 0.4, 0.6] {
+            for_each_small_case(1..=6, 50, exclusion_probability, &mut rng, |participants, constraints, rng| {
+                let expected = valid_assignments(participants.len(), &constraints);
+                match generate_secret_santa_with_rng(participants, &constraints, rng) {
+                    Ok(assignment) => {
+                        assert!(expected.contains(&to_recipients(&assignment)));
+                        assert_eq!(verify_assignment(participants, &constraints, &assignment), Ok(()));
+                    }
+                    Err(_) => assert!(expected.is_empty()),
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn seeded_draws_are_reproducible() {
+        let participants = generate_participants(30);
+        let mut rng = StdRng::seed_from_u64(1);
+        let constraints = Constraints::default().exclusions(generate_large_exclusions(&participants, 0.5, &mut rng));
+
+        let first = generate_secret_santa_seeded(participants.iter(), &constraints, 42).unwrap();
+        let reordered: Vec<_> = participants.iter().sorted().rev().collect();
+        let second = generate_secret_santa_seeded(reordered, &constraints, 42).unwrap();
+
+        assert_eq!(first.seed, second.seed);
+        assert_eq!(first.assignment, second.assignment);
+    }
+
+    #[test]
+    fn infeasible_draws_name_offenders() {
+        use crate::secret_santa::draw_error::DrawError;
+
+        let participants: Vec<usize> = (0..4).collect();
+        let [a, b, c, d] = [&participants[0], &participants[1], &participants[2], &participants[3]];
+
+        let constraints = Constraints::default().exclude(a, b).exclude(a, c).exclude(a, d);
+        assert_eq!(generate_secret_santa_seeded(&participants, &constraints, 0).err(), Some(DrawError::NoAllowedRecipients(a)));
+
+        let constraints = Constraints::default().exclude(a, b).exclude(a, d).exclude(b, a).exclude(b, d);
+        assert_eq!(
+            generate_secret_santa_seeded(&participants, &constraints, 0).err(),
+            Some(DrawError::HallViolation { givers: vec![a, b], recipients: vec![c] })
+        );
+    }
+
+    #[test]
+    fn derangements_split_into_several_cycles() {
+        use crate::secret_santa::draw_error::DrawError;
+
+        let participants: Vec<usize> = (0..4).collect();
+        let [a, b, c, d] = [&participants[0], &participants[1], &participants[2], &participants[3]];
+        let constraints = Constraints::default().exclude(a, c).exclude(a, d).exclude(b, c).exclude(b, d);
+
+        assert_eq!(generate_secret_santa_seeded(&participants, &constraints, 0).err(), Some(DrawError::SearchExhausted));
+
+        let draw = generate_secret_santa_seeded(&participants, &constraints.mode(DrawMode::Derangement), 0).unwrap();
+        assert_eq!(draw.assignment, HashMap::from([(a, b), (b, a), (c, d), (d, c)]));
+    }
+
+    #[test]
+    fn min_cycle_length_rules_out_short_cycles() {
+        let participants: Vec<usize> = (0..6).collect();
+        let constraints = Constraints::default().mode(DrawMode::Derangement).min_cycle_length(3);
+
+        for seed in 0..100 {
+            let draw = generate_secret_santa_seeded(&participants, &constraints, seed).unwrap();
+            assert!(cycle_lengths(&to_recipients(&draw.assignment)).iter().all(|&length| length >= 3));
+        }
+    }
+
+    /// Generates `years` random previous draws of `participants`, from the oldest to the most recent.
+    fn generate_history<'a, R: Rng>(participants: &'a [usize], years: usize, rng: &mut R) -> Vec<Assignment<'a, usize>> {
+        let constraints = Constraints::default().mode(DrawMode::Derangement);
+        (0..years).map(|_| generate_secret_santa_with_rng(participants, &constraints, rng).unwrap()).collect()
+    }
+
+    fn to_history<'a>(years: &[Assignment<'a, usize>]) -> History<'a, usize> {
+        years.iter().cloned().fold(History::default(), History::year)
+    }
+
+    #[test]
+    fn history_exclusions_prevent_repeats() {
+        use crate::secret_santa::history::HistoryPolicy;
+
+        let participants: Vec<usize> = (0..8).collect();
+        let mut rng = StdRng::seed_from_u64(2);
+        let years = generate_history(&participants, 3, &mut rng);
+        let constraints = Constraints::default().history(to_history(&years), HistoryPolicy::Exclude { years: 2 });
+
+        for seed in 0..50 {
+            let draw = generate_secret_santa_seeded(&participants, &constraints, seed).unwrap();
+            for year in &years[1..] {
+                assert!(draw.assignment.iter().all(|(giver, recipient)| year[giver] != *recipient));
+            }
+        }
+    }
+
+    #[test]
+    fn history_penalties_give_the_freshest_draw() {
+        use crate::secret_santa::history::HistoryPolicy;
+
+        let mut rng = StdRng::seed_from_u64(3);
+        for count in 3..=6 {
+            let participants: Vec<usize> = (0..count).collect();
+            for _ in 0..20 {
+                let exclusions = generate_large_exclusions(&participants, 0.2, &mut rng);
+                let years = generate_history(&participants, 3, &mut rng);
+                let cost = |recipient_of: &[usize]| -> f64 {
+                    let repeats = years.iter().rev().enumerate().flat_map(|(age, year)| {
+                        recipient_of.iter().enumerate().filter(move |&(giver, recipient)| year[&giver] == recipient).map(move |_| age)
+                    });
+                    repeats.map(|age| 0.5f64.powi(age as i32)).sum()
+                };
+
+                for (mode, min_cycle_length) in [(DrawMode::SingleCycle, 2), (DrawMode::Derangement, 2), (DrawMode::Derangement, 3)] {
+                    let constraints = Constraints::default()
+                        .exclusions(exclusions.clone())
+                        .mode(mode)
+                        .min_cycle_length(min_cycle_length)
+                        .history(to_history(&years), HistoryPolicy::Penalize { decay: 0.5 });
+                    let cheapest = valid_assignments(count, &constraints).iter().map(|recipient_of| cost(recipient_of)).reduce(f64::min);
+
+                    match generate_secret_santa_with_rng(&participants, &constraints, &mut rng) {
+                        Ok(assignment) => assert!((cost(&to_recipients(&assignment)) - cheapest.unwrap()).abs() < 1e-9),
+                        Err(_) => assert!(cheapest.is_none()),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pair_costs_give_the_cheapest_draw() {
+        let mut rng = StdRng::seed_from_u64(4);
+        for count in 3..=6 {
+            let participants: Vec<usize> = (0..count).collect();
+            for _ in 0..20 {
+                let exclusions = generate_large_exclusions(&participants, 0.2, &mut rng);
+                let costs: HashMap<_, _> = participants
+                    .iter()
+                    .cartesian_product(&participants)
+                    .map(|pair| (pair, rng.gen_range(-2..=2) as f64))
+                    .filter(|&(_, cost)| cost != 0.0)
+                    .collect();
+
+                for (mode, min_cycle_length) in [(DrawMode::SingleCycle, 2), (DrawMode::Derangement, 2), (DrawMode::Derangement, 3)] {
+                    let constraints = Constraints::default()
+                        .exclusions(exclusions.clone())
+                        .mode(mode)
+                        .min_cycle_length(min_cycle_length)
+                        .pair_costs(costs.clone());
+                    let cheapest = valid_assignments(count, &constraints)
+                        .iter()
+                        .map(|recipient_of| {
+                            let assignment = recipient_of.iter().enumerate().map(|(giver, recipient)| (&participants[giver], recipient)).collect();
+                            constraints.cost_of(&assignment)
+                        })
+                        .reduce(f64::min);
+
+                    match generate_secret_santa_seeded(&participants, &constraints, rng.gen()) {
+                        Ok(draw) => assert!((draw.cost - cheapest.unwrap()).abs() < 1e-9),
+                        Err(_) => assert!(cheapest.is_none()),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn everyone_gives_and_receives_several_gifts() {
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_gifts;
+
+        let participants: Vec<usize> = (0..12).collect();
+        let mut rng = StdRng::seed_from_u64(5);
+        let exclusions = generate_large_exclusions(&participants, 0.3, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions.clone());
+
+        for gifts in 1..=3 {
+            let draw = generate_secret_santa_with_gifts(&participants, &constraints, gifts, &mut rng).unwrap();
+            let mut received: HashMap<&usize, usize> = HashMap::new();
+            for (giver, recipients) in &draw {
+                assert_eq!(recipients.iter().unique().count(), gifts);
+                for recipient in recipients {
+                    assert_ne!(giver, recipient);
+                    assert!(!exclusions.get(giver).is_some_and(|excluded| excluded.contains(recipient)));
+                    *received.entry(recipient).or_default() += 1;
+                }
+            }
+            assert!(participants.iter().all(|participant| received[participant] == gifts));
+        }
+
+        let constraints = Constraints::default().exclude(&participants[0], &participants[1]);
+        assert_eq!(
+            generate_secret_santa_with_gifts(&participants[..3], &constraints, 2, &mut rng).err(),
+            Some(DrawError::SearchExhausted)
+        );
+        assert_eq!(
+            generate_secret_santa_with_gifts(&participants[..3], &constraints, 3, &mut rng).err(),
+            Some(DrawError::TooFewParticipants { count: 3, needed: 4 })
+        );
+    }
+
+    #[test]
+    fn household_members_never_give_to_each_other() {
+        use crate::secret_santa::group::Group;
+
+        let participants: Vec<usize> = (0..9).collect();
+        let households = [Group::new("A".into(), 0..3), Group::new("B".into(), 3..5), Group::new("C".into(), 5..9)];
+        let constraints = Constraints::default().groups(&households);
+
+        for seed in 0..50 {
+            let draw = generate_secret_santa_seeded(&participants, &constraints, seed).unwrap();
+            for household in &households {
+                assert!(draw.assignment.iter().all(|(giver, recipient)| {
+                    !household.members.contains(giver) || !household.members.contains(recipient)
+                }));
+            }
+        }
+    }
+
+    /// Checks with Pearson's chi-squared test that `observed` counts are plausible for a uniform distribution.
+    fn looks_uniform(observed: &[usize]) -> bool {
+        let total: usize = observed.iter().sum();
+        let expected = total as f64 / observed.len() as f64;
+        let statistic: f64 = observed.iter().map(|&count| (count as f64 - expected).powi(2) / expected).sum();
+
+        // Wilson-Hilferty approximation of the chi-squared quantile for a 0.05% significance level
+        let freedom = (observed.len() - 1) as f64;
+        let spread = 2.0 / (9.0 * freedom);
+        statistic < freedom * (1.0 - spread + 3.29 * spread.sqrt()).powi(3)
+    }
+
+    #[test]
+    fn sampling_is_uniform_over_valid_assignments() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::sampling::SamplingMethod;
+        use crate::secret_santa::secret_santa::sample_secret_santa_with_rng;
+
+        let mut rng = StdRng::seed_from_u64(6);
+        for (count, mode, min_cycle_length) in [(5, DrawMode::SingleCycle, 2), (5, DrawMode::Derangement, 2), (6, DrawMode::Derangement, 3)] {
+            let participants: Vec<usize> = (0..count).collect();
+            let constraints = Constraints::default()
+                .exclude(&participants[0], &participants[1])
+                .exclude(&participants[2], &participants[3])
+                .exclude(&participants[4], &participants[0])
+                .mode(mode)
+                .min_cycle_length(min_cycle_length);
+            let valid = valid_assignments(count, &constraints);
+
+            for method in [SamplingMethod::Rejection { attempts: 10_000 }, SamplingMethod::MarkovChain { steps: 100 }] {
+                let mut observed = vec![0; valid.len()];
+                for _ in 0..100 * valid.len() {
+                    let assignment =
+                        sample_secret_santa_with_rng(&participants, &constraints, method, &mut SearchControl::default(), &mut rng).unwrap();
+                    let position = valid.iter().position(|recipient_of| *recipient_of == to_recipients(&assignment));
+                    observed[position.unwrap()] += 1;
+                }
+                assert!(looks_uniform(&observed), "{:?} isn't uniform for {:?}: {:?}", method, mode, observed);
+            }
+        }
+
+        // Cancelling stops sampling as it does searches
+        let participants: Vec<usize> = (0..30).collect();
+        let token = CancelToken::default();
+        token.cancel();
+        for method in [SamplingMethod::Rejection { attempts: 10_000 }, SamplingMethod::MarkovChain { steps: 100 }] {
+            let mut control = SearchControl::default().cancel_token(token.clone());
+            assert_eq!(
+                sample_secret_santa_with_rng(&participants, &Constraints::default(), method, &mut control, &mut rng),
+                Err(DrawError::Interrupted(Interruption::Cancelled))
+            );
+        }
+    }
+
+    #[test]
+    fn counting_and_enumeration_agree_with_brute_force() {
+        use crate::secret_santa::counting::AssignmentCount;
+        use crate::secret_santa::secret_santa::{count_secret_santa_with_rng, enumerate_secret_santa};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for exclusion_probability in [0.0, 0.3, 0.6] {
+            for_each_small_case(1..=7, 10, exclusion_probability, &mut rng, |participants, constraints, rng| {
+                let expected = valid_assignments(participants.len(), &constraints);
+                let count = count_secret_santa_with_rng(participants, &constraints, rng);
+                assert_eq!(count, AssignmentCount::Exact(expected.len() as u128));
+
+                let enumerated: Vec<Vec<usize>> = enumerate_secret_santa(participants, &constraints)
+                    .map(|assignment| to_recipients(&assignment))
+                    .sorted()
+                    .collect();
+                assert_eq!(enumerated, expected);
+            });
+        }
+    }
+
+    #[test]
+    fn large_counts_are_estimated() {
+        use crate::secret_santa::counting::AssignmentCount;
+        use crate::secret_santa::secret_santa::count_secret_santa_with_rng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let participants: Vec<usize> = (0..30).collect();
+
+        // Single cycles through 30 participants are the 29! orders of the 29 others after any one of them
+        let constraints = Constraints::default();
+        let log10_factorial_29: f64 = (1..=29).map(|i| (i as f64).log10()).sum();
+        match count_secret_santa_with_rng(&participants, &constraints, &mut rng) {
+            AssignmentCount::Estimated { log10 } => assert!((log10 - log10_factorial_29).abs() < 0.5, "{}", log10),
+            AssignmentCount::Exact(_) => panic!("30 participants should be estimated"),
+        }
+
+        // Giving only to the next participant leaves a single possibility
+        let exclusions = participants
+            .iter()
+            .map(|giver| (giver, participants.iter().filter(|&&r| r != (giver + 1) % 30).collect()))
+            .collect();
+        let constraints = Constraints::default().exclusions(exclusions);
+        match count_secret_santa_with_rng(&participants, &constraints, &mut rng) {
+            AssignmentCount::Estimated { log10 } => assert!(log10.abs() < 1e-9, "{}", log10),
+            AssignmentCount::Exact(_) => panic!("30 participants should be estimated"),
+        }
+    }
+
+    #[test]
+    fn relaxations_drop_the_fewest_exclusions() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::secret_santa::suggest_relaxations_with_rng;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(2..=6, 30, 0.7, &mut rng, |participants, constraints, rng| {
+            let (exclusions, mode, min_cycle_length) = (&constraints.exclusions, constraints.mode, constraints.min_cycle_length);
+            let unconstrained = Constraints::default().mode(mode).min_cycle_length(min_cycle_length);
+            let fewest = valid_assignments(participants.len(), &unconstrained)
+                .iter()
+                .map(|recipient_of| {
+                    let excluded = |(giver, recipient): (usize, &usize)| exclusions.get(&giver).is_some_and(|ex| ex.contains(recipient));
+                    recipient_of.iter().enumerate().filter(|&pair| excluded(pair)).count()
+                })
+                .min();
+
+            let suggestions = suggest_relaxations_with_rng(participants, &constraints, 3, &mut SearchControl::default(), rng).unwrap();
+            let Some(fewest) = fewest else {
+                assert!(suggestions.is_empty());
+                return;
+            };
+            assert!(!suggestions.is_empty() && suggestions.len() <= 3);
+            assert_eq!(suggestions[0].len(), fewest);
+            assert!(suggestions.iter().all_unique());
+
+            for relaxation in &suggestions {
+                let mut relaxed = exclusions.clone();
+                for (giver, recipient) in relaxation {
+                    assert!(relaxed.get_mut(giver).is_some_and(|ex| ex.remove(recipient)));
+                }
+                let constraints = Constraints::default().exclusions(relaxed).mode(mode).min_cycle_length(min_cycle_length);
+                assert!(generate_secret_santa_with_rng(participants, &constraints, rng).is_ok());
+            }
+        });
+
+        // A cancelled search has nothing to suggest yet
+        let participants: Vec<usize> = (0..6).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.7, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions);
+        let cancelled = CancelToken::default();
+        cancelled.cancel();
+        let mut control = SearchControl::default().cancel_token(cancelled);
+        assert_eq!(
+            suggest_relaxations_with_rng(&participants, &constraints, 3, &mut control, &mut rng),
+            Err(Interruption::Cancelled)
+        );
+    }
+
+    #[test]
+    fn required_pairs_are_always_drawn() {
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_gifts;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(2..=6, 50, 0.3, &mut rng, |participants, constraints, rng| {
+            let count = participants.len();
+            let giver = rng.gen_range(0..count);
+            let recipient = (giver + rng.gen_range(1..count)) % count;
+            let constraints = constraints.require(&participants[giver], &participants[recipient]);
+            let expected: Vec<Vec<usize>> = valid_assignments(count, &constraints)
+                .into_iter()
+                .filter(|recipient_of| recipient_of[giver] == recipient)
+                .collect();
+            match generate_secret_santa_with_rng(participants, &constraints, rng) {
+                Ok(assignment) => {
+                    assert!(expected.contains(&to_recipients(&assignment)));
+                    assert_eq!(verify_assignment(participants, &constraints, &assignment), Ok(()));
+                }
+                Err(_) => assert!(expected.is_empty()),
+            }
+        });
+
+        let participants: Vec<usize> = (0..5).collect();
+        let p = |i: usize| &participants[i];
+        let constraints = Constraints::default().require(p(0), p(1)).require(p(0), p(2)).require(p(3), p(1));
+        for _ in 0..20 {
+            let draw = generate_secret_santa_with_gifts(&participants, &constraints, 2, &mut rng).unwrap();
+            assert!(draw[p(0)].contains(&p(1)) && draw[p(0)].contains(&p(2)) && draw[p(3)].contains(&p(1)));
+        }
+    }
+
+    #[test]
+    fn conflicting_requirements_are_reported() {
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_gifts;
+        let participants: Vec<usize> = (0..5).collect();
+        let p = |i: usize| &participants[i];
+
+        let excluded = Constraints::default().exclude(p(0), p(1)).require(p(0), p(1));
+        assert_eq!(
+            generate_secret_santa_with_rng(&participants, &excluded, &mut StdRng::seed_from_u64(0)).unwrap_err(),
+            DrawError::RequiredPairExcluded { giver: p(0), recipient: p(1) }
+        );
+
+        let same_giver = Constraints::default().require(p(0), p(1)).require(p(0), p(2));
+        assert_eq!(
+            generate_secret_santa_with_rng(&participants, &same_giver, &mut StdRng::seed_from_u64(0)).unwrap_err(),
+            DrawError::ConflictingRequirements { first: (p(0), p(1)), second: (p(0), p(2)) }
+        );
+
+        let too_short = Constraints::default().require(p(0), p(1)).require(p(1), p(2)).require(p(2), p(0));
+        assert_eq!(
+            generate_secret_santa_with_rng(&participants, &too_short, &mut StdRng::seed_from_u64(0)).unwrap_err(),
+            DrawError::RequiredCycleTooShort(vec![p(0), p(1), p(2)])
+        );
+        let derangement = too_short.mode(DrawMode::Derangement);
+        assert!(generate_secret_santa_with_rng(&participants, &derangement, &mut StdRng::seed_from_u64(0)).is_ok());
+
+        let outsider = 7;
+        let unknown = Constraints::default().require(p(0), &outsider);
+        assert_eq!(
+            generate_secret_santa_with_rng(&participants, &unknown, &mut StdRng::seed_from_u64(0)).unwrap_err(),
+            DrawError::UnknownParticipant(&outsider)
+        );
+
+        let required = Constraints::default().require(p(0), p(1));
+        assert_eq!(
+            generate_secret_santa_with_gifts(&participants, &required, 0, &mut StdRng::seed_from_u64(0)).unwrap_err(),
+            DrawError::NoGifts
+        );
+    }
+
+    #[test]
+    fn dropouts_change_the_fewest_pairs() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::repair_after_dropout;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(3..=7, 30, 0.3, &mut rng, |participants, constraints, rng| {
+            // The last participant drops out, which leaves the others numbered as `valid_assignments` expects
+            let count = participants.len();
+            let dropout = &participants[count - 1];
+            let Ok(assignment) = generate_secret_santa_with_rng(participants, &constraints, rng) else { return };
+            let before = to_recipients(&assignment);
+            let changes = |after: &[usize]| after.iter().zip(&before).filter(|(after, before)| after != before).count();
+            let fewest = valid_assignments(count - 1, &constraints).iter().map(|after| changes(after)).min();
+
+            match repair_after_dropout(&assignment, dropout, &constraints, &mut SearchControl::default(), rng) {
+                Ok(repaired) => {
+                    let after = to_recipients(&repaired);
+                    assert!(valid_assignments(count - 1, &constraints).contains(&after));
+                    assert_eq!(Some(changes(&after)), fewest);
+                }
+                Err(_) => assert_eq!(fewest, None),
+            }
+        });
+
+        // Without exclusions, only the giver of the dropout changes
+        let participants: Vec<usize> = (0..30).collect();
+        let constraints = Constraints::default();
+        let assignment = generate_secret_santa_with_rng(&participants, &constraints, &mut rng).unwrap();
+        let repaired = repair_after_dropout(&assignment, &participants[7], &constraints, &mut SearchControl::default(), &mut rng).unwrap();
+        let giver = participants.iter().find(|&giver| assignment[giver] == &participants[7]).unwrap();
+        for (giver_after, recipient) in &repaired {
+            let expected = if giver_after == &giver { assignment[&participants[7]] } else { assignment[giver_after] };
+            assert_eq!(recipient, &expected);
+        }
+
+        // When the giver of the dropout can't take over their recipient, the search can be stopped
+        let participants: Vec<usize> = (0..8).collect();
+        let p = &participants;
+        let assignment: Assignment<usize> = (0..8).map(|i| (&p[i], &p[(i + 1) % 8])).collect();
+        let constraints = Constraints::default().exclude(&p[2], &p[4]);
+        let cancelled = CancelToken::default();
+        cancelled.cancel();
+        let mut control = SearchControl::default().cancel_token(cancelled);
+        assert_eq!(
+            repair_after_dropout(&assignment, &p[3], &constraints, &mut control, &mut rng),
+            Err(DrawError::Interrupted(Interruption::Cancelled))
+        );
+        let repaired = repair_after_dropout(&assignment, &p[3], &constraints, &mut SearchControl::default(), &mut rng).unwrap();
+        assert_eq!(verify_assignment(p.iter().filter(|&&participant| participant != 3), &constraints, &repaired), Ok(()));
+    }
+
+    #[test]
+    fn late_joiners_change_one_or_two_pairs() {
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::add_late_joiner;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(4..=7, 30, 0.4, &mut rng, |participants, constraints, rng| {
+            // The last participant joins late
+            let count = participants.len() - 1;
+            let (joiner, before) = (&participants[count], &participants[..count]);
+            let Ok(assignment) = generate_secret_santa_with_rng(before, &constraints, rng) else { return };
+            let excluded = |giver: &usize, recipient: &usize| constraints.exclusions.get(giver).is_some_and(|ex| ex.contains(recipient));
+            let allowed = |giver: &usize, recipient: &usize| giver != recipient && !excluded(giver, recipient);
+            let one_edge = assignment.iter().any(|(giver, recipient)| allowed(giver, joiner) && allowed(joiner, recipient));
+
+            match add_late_joiner(&assignment, joiner, &constraints, rng) {
+                Ok(joined) => {
+                    let after = to_recipients(&joined.assignment);
+                    assert!(valid_assignments(count + 1, &constraints).contains(&after));
+                    let changed: Vec<&usize> = before.iter().filter(|giver| joined.assignment[giver] != assignment[giver]).collect();
+                    assert_eq!(joined.renotified.iter().sorted().collect_vec(), changed.iter().sorted().collect_vec());
+                    assert_eq!(changed.len(), if one_edge { 1 } else { 2 });
+                }
+                Err(error) => {
+                    assert!(matches!(error, DrawError::NoRoomForJoiner(who) if who == joiner));
+                    assert!(!one_edge);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn every_solver_draws_valid_assignments() {
+        use crate::secret_santa::control::SearchControl;
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_solver;
+        use crate::secret_santa::solver::{solver_named, solvers};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(2..=6, 20, 0.3, &mut rng, |participants, constraints, rng| {
+            let expected = valid_assignments(participants.len(), &constraints);
+            for solver in solvers::<usize>() {
+                // Only exhaustive solvers have to find an assignment whenever one exists, others may take long to give up
+                let exhaustive = ["backtracking", "sat", "portfolio"].contains(&solver.name());
+                if expected.is_empty() && !exhaustive {
+                    continue;
+                }
+                match generate_secret_santa_with_solver(participants, &constraints, solver.as_ref(), &mut SearchControl::default(), rng) {
+                    Ok(assignment) => {
+                        assert!(expected.contains(&to_recipients(&assignment)), "{}", solver.name());
+                        assert_eq!(verify_assignment(participants, &constraints, &assignment), Ok(()), "{}", solver.name());
+                    }
+                    Err(_) => assert!(expected.is_empty() || !exhaustive),
+                }
+            }
+        });
+
+        for solver in solvers::<usize>() {
+            assert!(solver_named::<usize>(solver.name()).is_some_and(|named| named.name() == solver.name()));
+        }
+    }
+
+    #[test]
+    fn sat_solver_handles_combined_constraints() {
+        use crate::secret_santa::history::HistoryPolicy;
+        use crate::secret_santa::control::SearchControl;
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_solver;
+        use crate::secret_santa::solver::SatSolving;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for count in 4..=7 {
+            let participants: Vec<usize> = (0..count).collect();
+            for _ in 0..20 {
+                let exclusions = generate_large_exclusions(&participants, 0.3, &mut rng);
+                let years = generate_history(&participants, 2, &mut rng);
+                let (giver, recipient) = (rng.gen_range(0..count), rng.gen_range(0..count));
+                let (preferred_giver, preferred) = (rng.gen_range(0..count), rng.gen_range(0..count));
+                for (mode, min_cycle_length) in [(DrawMode::SingleCycle, 2), (DrawMode::Derangement, 3)] {
+                    let constraints = Constraints::default()
+                        .exclusions(exclusions.clone())
+                        .mode(mode)
+                        .min_cycle_length(min_cycle_length)
+                        .history(to_history(&years), HistoryPolicy::Penalize { decay: 0.5 })
+                        .pair_cost(&participants[preferred_giver], &participants[preferred], -10.0);
+                    let constraints = match giver != recipient {
+                        true => constraints.require(&participants[giver], &participants[recipient]),
+                        false => constraints,
+                    };
+
+                    // The total cost of the draw, preferred pair included, is as low as possible
+                    let total_cost = |recipient_of: &[usize]| {
+                        let assignment = recipient_of.iter().enumerate().map(|(giver, recipient)| (&participants[giver], recipient)).collect();
+                        constraints.cost_of(&assignment)
+                    };
+                    let expected: Vec<Vec<usize>> = valid_assignments(count, &constraints)
+                        .into_iter()
+                        .filter(|recipient_of| giver == recipient || recipient_of[giver] == recipient)
+                        .collect();
+                    let cheapest = expected.iter().map(|recipient_of| total_cost(recipient_of)).fold(f64::INFINITY, f64::min);
+
+                    let seed = rng.gen();
+                    match generate_secret_santa_with_solver(&participants, &constraints, &SatSolving, &mut SearchControl::default(), &mut StdRng::seed_from_u64(seed)) {
+                        Ok(assignment) => {
+                            let recipient_of = to_recipients(&assignment);
+                            assert!(expected.contains(&recipient_of));
+                            assert_eq!(verify_assignment(&participants, &constraints, &assignment), Ok(()));
+                            assert!((total_cost(&recipient_of) - cheapest).abs() < 1e-9);
+                            let again = generate_secret_santa_with_solver(&participants, &constraints, &SatSolving, &mut SearchControl::default(), &mut StdRng::seed_from_u64(seed));
+                            assert_eq!(again.map(|assignment| to_recipients(&assignment)), Ok(recipient_of));
+                        }
+                        Err(_) => assert!(expected.is_empty()),
+                    }
+                }
+            }
+        }
+
+        // Large single cycles only need a few rounds of lazily added clauses
+        let participants: Vec<usize> = (0..80).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.6, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions);
+        let assignment = generate_secret_santa_with_solver(&participants, &constraints, &SatSolving, &mut SearchControl::default(), &mut rng).unwrap();
+        assert_eq!(verify_assignment(&participants, &constraints, &assignment), Ok(()));
+        assert_eq!(cycle_lengths(&to_recipients(&assignment)), vec![80]);
+    }
+
+    #[test]
+    fn search_limits_interrupt_draws() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_solver;
+        use crate::secret_santa::solver::solver_named;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let participants: Vec<usize> = (0..60).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.5, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions);
+
+        let cancelled = CancelToken::default();
+        cancelled.cancel();
+        for name in ["backtracking", "sat"] {
+            let solver = solver_named::<usize>(name).unwrap();
+            let mut draw = |mut control: SearchControl| {
+                generate_secret_santa_with_solver(&participants, &constraints, solver.as_ref(), &mut control, &mut rng).err()
+            };
+            assert_eq!(draw(SearchControl::default().node_limit(10)), Some(DrawError::Interrupted(Interruption::OutOfNodes)));
+            assert_eq!(draw(SearchControl::default().time_limit(Duration::ZERO)), Some(DrawError::Interrupted(Interruption::TimedOut)));
+            assert_eq!(draw(SearchControl::default().cancel_token(cancelled.clone())), Some(DrawError::Interrupted(Interruption::Cancelled)));
+            assert_eq!(draw(SearchControl::default().cancel_token(CancelToken::default())), None);
+
+            // Progress is reported as the search goes
+            let mut reports = Vec::new();
+            let mut control = SearchControl::default().on_progress(|progress| reports.push(progress));
+            generate_secret_santa_with_solver(&participants, &constraints, solver.as_ref(), &mut control, &mut rng).unwrap();
+            drop(control);
+            assert!(!reports.is_empty());
+            assert!(reports.windows(2).all(|pair| pair[0].nodes <= pair[1].nodes));
+            assert!(reports.iter().all(|progress| progress.depth <= progress.max_depth));
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn portfolio_draws_are_reproducible() {
+        use crate::secret_santa::secret_santa::generate_secret_santa_with_solver;
+        use crate::secret_santa::solver::solver_named;
+        use rayon::ThreadPoolBuilder;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let participants: Vec<usize> = (0..150).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.9, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions).mode(DrawMode::Derangement).min_cycle_length(3);
+
+        // However many threads run the searches, the same seed gives the same draw with the default portfolio
+        let draw = |threads: usize, seed: u64| {
+            let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let solver = solver_named::<usize>("portfolio").unwrap();
+                let mut rng = StdRng::seed_from_u64(seed);
+                generate_secret_santa_with_solver(&participants, &constraints, solver.as_ref(), &mut SearchControl::default(), &mut rng)
+            })
+        };
+        for seed in 0..3 {
+            let assignment = draw(1, seed).unwrap();
+            assert_eq!(verify_assignment(&participants, &constraints, &assignment), Ok(()));
+            assert_eq!(draw(4, seed), Ok(assignment));
+        }
+
+        // Node limits below the budget of a round still leave room for easy draws, which report how the winning
+        // search went
+        let participants: Vec<usize> = (0..10).collect();
+        let solver = solver_named::<usize>("portfolio").unwrap();
+        let mut control = SearchControl::default().node_limit(5000).record_trace(1000);
+        let draw = generate_secret_santa_with_solver(&participants, &Constraints::default(), solver.as_ref(), &mut control, &mut rng);
+        assert!(draw.is_ok());
+        assert!(control.stats().nodes > 0 && control.stats().max_depth > 0);
+        assert!(control.stats().phases.iter().any(|&(phase, _)| phase == "search"));
+        assert!(!control.events().0.is_empty());
+    }
+
+    #[test]
+    fn bitsets_agree_with_index_lists() {
+        use crate::secret_santa::bitset::BitSet;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for capacity in [0, 1, 63, 64, 65, 200] {
+            let (mut first, mut second) = (BitSet::new(capacity), BitSet::full(capacity));
+            let (mut in_first, mut in_second) = (vec![false; capacity], vec![true; capacity]);
+            for _ in 0..capacity * 2 {
+                let index = rng.gen_range(0..capacity);
+                first.insert(index);
+                in_first[index] = true;
+                let index = rng.gen_range(0..capacity);
+                second.remove(index);
+                in_second[index] = false;
+            }
+
+            let listed = |present: &[bool]| (0..capacity).filter(|&i| present[i]).collect::<Vec<_>>();
+            assert_eq!(first.iter().collect::<Vec<_>>(), listed(&in_first));
+            assert_eq!(second.iter().collect::<Vec<_>>(), listed(&in_second));
+            let both: Vec<bool> = (0..capacity).map(|i| in_first[i] && in_second[i]).collect();
+            assert_eq!(first.intersection(&second).collect::<Vec<_>>(), listed(&both));
+            assert!((0..capacity).all(|i| first.contains(i) == in_first[i]));
+        }
+    }
+
+    #[test]
+    fn large_events_are_drawn() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let participants: Vec<usize> = (0..800).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.3, &mut rng);
+        for constraints in [
+            Constraints::default().exclusions(exclusions.clone()).mode(DrawMode::Derangement).min_cycle_length(3),
+            Constraints::default().exclusions(exclusions.clone()),
+        ] {
+            let assignment = generate_secret_santa_with_rng(&participants, &constraints, &mut rng).unwrap();
+            assert_eq!(verify_assignment(&participants, &constraints, &assignment), Ok(()));
+        }
+    }
+
+    #[test]
+    fn verification_reports_every_violation() {
+        use crate::secret_santa::history::HistoryPolicy;
+        use crate::secret_santa::violation::Violation;
+
+        let participants: Vec<usize> = (0..6).collect();
+        let p = &participants;
+        let year: Assignment<usize> = HashMap::from([(&p[0], &p[1])]);
+        let constraints = Constraints::default()
+            .exclude(&p[2], &p[3])
+            .require(&p[4], &p[5])
+            .mode(DrawMode::Derangement)
+            .min_cycle_length(3)
+            .history(to_history(&[year]), HistoryPolicy::Exclude { years: 1 });
+
+        let valid: Assignment<usize> = HashMap::from([(&p[0], &p[2]), (&p[2], &p[1]), (&p[1], &p[0]), (&p[3], &p[4]), (&p[4], &p[5]), (&p[5], &p[3])]);
+        assert_eq!(verify_assignment(p, &constraints, &valid), Ok(()));
+
+        // A permutation breaking rules between participants
+        let broken: Assignment<usize> = HashMap::from([(&p[0], &p[1]), (&p[1], &p[0]), (&p[2], &p[3]), (&p[3], &p[2]), (&p[4], &p[4]), (&p[5], &p[5])]);
+        assert_eq!(
+            verify_assignment(p, &constraints, &broken),
+            Err(vec![
+                Violation::RepeatsHistory { giver: &p[0], recipient: &p[1] },
+                Violation::Excluded { giver: &p[2], recipient: &p[3] },
+                Violation::SelfGift(&p[4]),
+                Violation::SelfGift(&p[5]),
+                Violation::MissingRequiredPair { giver: &p[4], recipient: &p[5] },
+                Violation::CycleTooShort(vec![&p[0], &p[1]]),
+                Violation::CycleTooShort(vec![&p[2], &p[3]]),
+            ])
+        );
+
+        // Not a permutation of the participants
+        let outsider = 9;
+        let scrambled: Assignment<usize> = HashMap::from([(&p[0], &p[2]), (&p[1], &p[2]), (&p[3], &p[4]), (&p[4], &p[5]), (&p[5], &outsider), (&outsider, &p[0])]);
+        assert_eq!(
+            verify_assignment(p, &constraints, &scrambled),
+            Err(vec![
+                Violation::UnknownRecipient(&outsider),
+                Violation::UnknownGiver(&outsider),
+                Violation::ReceivesNothing(&p[1]),
+                Violation::GivesNothing(&p[2]),
+                Violation::ReceivesSeveral { recipient: &p[2], givers: vec![&p[0], &p[1]] },
+                Violation::ReceivesNothing(&p[3]),
+            ])
+        );
+    }
+
+    #[test]
+    fn searches_report_stats_and_traces() {
+        use crate::secret_santa::secret_santa::search_trace;
+        use crate::secret_santa::trace::{Trace, TraceEvent};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let participants: Vec<usize> = (0..40).collect();
+        let exclusions = generate_large_exclusions(&participants, 0.8, &mut rng);
+        let constraints = Constraints::default().exclusions(exclusions);
+
+        let mut control = SearchControl::default().record_trace(1_000_000);
+        generate_secret_santa_with_control(&participants, &constraints, &mut control, &mut rng).unwrap();
+        let stats = control.stats().clone();
+        let phases: Vec<&str> = stats.phases.iter().map(|&(phase, _)| phase).collect();
+        assert_eq!(phases, vec!["constraints", "feasibility", "search"]);
+        assert!(stats.nodes > 0 && stats.max_depth == participants.len());
+
+        // Every node is a participant joining, after the first participant of the first cycle
+        let trace = search_trace(&participants, &control);
+        assert!(!trace.truncated);
+        let pushes = trace.events.iter().filter(|event| matches!(event, TraceEvent::Push { .. })).count();
+        assert_eq!(pushes as u64, stats.nodes + 1);
+        let pops = trace.events.iter().filter(|event| matches!(event, TraceEvent::Pop { .. })).count();
+        assert_eq!(trace.path_at(trace.events.len()).len(), pushes - pops);
+
+        let mut written = Vec::new();
+        trace.write_to(&mut written).unwrap();
+        assert_eq!(Trace::read_from(written.as_slice()).unwrap(), trace);
+        assert!(Trace::read_from("participant 0\npush 1\n".as_bytes()).is_err());
+
+        let mut control = SearchControl::default().record_trace(5);
+        generate_secret_santa_with_control(&participants, &constraints, &mut control, &mut rng).unwrap();
+        let trace = search_trace(&participants, &control);
+        assert!(trace.truncated && trace.events.len() == 5);
+    }
+
+    #[test]
+    fn chains_pass_gifts_from_the_starter() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::generate_secret_santa_chain;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for count in 2..=6 {
+            let participants: Vec<usize> = (0..count).collect();
+            for _ in 0..30 {
+                let exclusions = generate_large_exclusions(&participants, 0.4, &mut rng);
+                let mut constraints = Constraints::default().exclusions(exclusions.clone());
+                let can_give = |giver: usize, recipient: usize| giver != recipient && !exclusions[&giver].contains(&recipient);
+                let starter = rng.gen_range(0..count);
+                let required = Some((rng.gen_range(0..count), rng.gen_range(0..count)))
+                    .filter(|&(giver, recipient)| rng.gen_bool(0.5) && can_give(giver, recipient));
+                if let Some((giver, recipient)) = required {
+                    constraints = constraints.require(&participants[giver], &participants[recipient]);
+                }
+                let passes_required = |order: &[usize]| required.is_none_or(|(giver, recipient)| order.windows(2).any(|pair| pair == [giver, recipient]));
+
+                // Every order of participants starting from the starter, passing gifts only where allowed
+                let possible = participants
+                    .iter()
+                    .copied()
+                    .permutations(count)
+                    .filter(|order| order[0] == starter && order.windows(2).all(|pair| can_give(pair[0], pair[1])))
+                    .filter(|order| passes_required(order))
+                    .count();
+                let start = Some(&participants[starter]);
+                match generate_secret_santa_chain(&participants, &constraints, start, &mut SearchControl::default(), &mut rng) {
+                    Ok(chain) => {
+                        let order: Vec<usize> = chain.order.iter().map(|&&participant| participant).collect();
+                        assert_eq!(order.iter().copied().sorted().collect::<Vec<_>>(), participants);
+                        assert_eq!(order[0], starter);
+                        assert!(order.windows(2).all(|pair| can_give(pair[0], pair[1])));
+                        assert!(passes_required(&order));
+                        assert_eq!(chain.closes, can_give(order[count - 1], order[0]));
+
+                        let reveals = chain.reveal_order();
+                        assert_eq!(reveals.len(), count - 1 + chain.closes as usize);
+                        assert!(reveals.iter().all(|&(&giver, &recipient)| can_give(giver, recipient)));
+                    }
+                    Err(_) => assert_eq!(possible, 0),
+                }
+            }
+        }
+
+        let participants: Vec<usize> = (0..3).collect();
+        let outsider = 7;
+        assert_eq!(
+            generate_secret_santa_chain(&participants, &Constraints::default(), Some(&outsider), &mut SearchControl::default(), &mut rng)
+                .err(),
+            Some(DrawError::UnknownParticipant(&outsider))
+        );
+
+        let token = CancelToken::default();
+        token.cancel();
+        let mut cancelled = SearchControl::default().cancel_token(token);
+        assert_eq!(
+            generate_secret_santa_chain(&participants, &Constraints::default(), None, &mut cancelled, &mut rng).err(),
+            Some(DrawError::Interrupted(Interruption::Cancelled))
+        );
+    }
+
+    #[test]
+    fn rounds_never_repeat_a_pair() {
+        use crate::secret_santa::control::{CancelToken, Interruption};
+        use crate::secret_santa::draw_error::DrawError;
+        use crate::secret_santa::secret_santa::generate_secret_santa_rounds;
+
+        // Whether `rounds` more of `valid` can be picked, from `from` on, sharing no pair with each other or `used`
+        fn disjoint(valid: &[Vec<usize>], from: usize, rounds: usize, used: &mut HashSet<(usize, usize)>) -> bool {
+            if rounds == 0 {
+                return true;
+            }
+            (from..valid.len()).any(|index| {
+                let pairs: Vec<(usize, usize)> = valid[index].iter().copied().enumerate().collect();
+                if pairs.iter().any(|pair| used.contains(pair)) {
+                    return false;
+                }
+                used.extend(pairs.iter().copied());
+                let found = disjoint(valid, index + 1, rounds - 1, used);
+                pairs.iter().for_each(|pair| {
+                    used.remove(pair);
+                });
+                found
+            })
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for_each_small_case(2..=6, 8, 0.2, &mut rng, |participants, constraints, rng| {
+            let valid = valid_assignments(participants.len(), &constraints);
+            for rounds in 1..=3 {
+                let possible = participants.len() > rounds && disjoint(&valid, 0, rounds, &mut HashSet::new());
+                match generate_secret_santa_rounds(participants, &constraints, rounds, &mut SearchControl::default(), rng) {
+                    Ok(draws) => {
+                        assert!(possible);
+                        assert_eq!(draws.len(), rounds);
+                        let mut pairs = HashSet::new();
+                        for draw in &draws {
+                            assert_eq!(verify_assignment(participants, &constraints, draw), Ok(()));
+                            assert!(draw.iter().all(|pair| pairs.insert(pair)));
+                        }
+                    }
+                    Err(DrawError::Interrupted(interruption)) => panic!("{}", interruption),
+                    Err(_) => assert!(!possible),
+                }
+            }
+        });
+
+        // Required pairs are all drawn in the first round, leaving later rounds free to use other recipients
+        let participants: Vec<usize> = (0..6).collect();
+        let p = |i: usize| &participants[i];
+        for (mode, min_cycle_length) in [(DrawMode::SingleCycle, 2), (DrawMode::Derangement, 2), (DrawMode::Derangement, 3)] {
+            let free = || Constraints::default().mode(mode).min_cycle_length(min_cycle_length);
+            let constraints = free().require(p(0), p(1)).require(p(2), p(3));
+            for rounds in 2..=3 {
+                let draws = generate_secret_santa_rounds(&participants, &constraints, rounds, &mut SearchControl::default(), &mut rng).unwrap();
+                assert_eq!(draws.len(), rounds);
+                assert_eq!(verify_assignment(&participants, &constraints, &draws[0]), Ok(()));
+                let mut pairs = HashSet::new();
+                for draw in &draws {
+                    assert_eq!(verify_assignment(&participants, &free(), draw), Ok(()));
+                    assert!(draw.iter().all(|pair| pairs.insert(pair)));
+                }
+            }
+        }
+
+        // Cycle rules need a search, which callers can stop
+        let token = CancelToken::default();
+        token.cancel();
+        let mut cancelled = SearchControl::default().cancel_token(token);
+        let constraints = Constraints::default().mode(DrawMode::Derangement).min_cycle_length(3);
+        assert_eq!(
+            generate_secret_santa_rounds(&participants, &constraints, 2, &mut cancelled, &mut rng),
+            Err(DrawError::Interrupted(Interruption::Cancelled))
+        );
+    }
+}
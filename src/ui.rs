@@ -1,4 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use eframe::egui;
 use eframe::egui::{Align, Color32, Frame, Id, Layout, Rounding, Sense};
 use eframe::egui::Key::{Backspace};
@@ -10,6 +15,12 @@ use rnglib::{Language, RNG};
 use crate::listview::item_trait::ItemTrait;
 use crate::listview::listview::ListView;
 use crate::participant::Participant;
+use crate::secret_santa::constraints::Constraints;
+use crate::secret_santa::control::{CancelToken, Progress, SearchControl, SearchStats};
+use crate::secret_santa::group::Group;
+use crate::secret_santa::secret_santa::{generate_secret_santa_with_solver, search_trace};
+use crate::secret_santa::solver::{solvers, DrawSolver};
+use crate::secret_santa::trace::{Trace, TraceEvent};
 use crate::test::generate_participants;
 
 impl ItemTrait for Participant {
@@ -54,11 +65,157 @@ impl ItemTrait for Participant {
 }
 
 
+/// Search steps recorded per draw, enough to replay the start of a slow search.
+const TRACE_LIMIT: usize = 100_000;
+
+/// What a worker thread sends back once its draw is done.
+struct FinishedDraw {
+    /// One line per pair, or why the draw failed.
+    result: Result<Vec<String>, String>,
+    stats: SearchStats,
+    trace: Trace,
+}
+
+/// A draw running on a worker thread, which sends its result back once done.
+struct RunningDraw {
+    result: Receiver<FinishedDraw>,
+    cancel: CancelToken,
+    /// The latest progress the search reported.
+    progress: Arc<Mutex<Option<Progress>>>,
+}
+
+impl RunningDraw {
+    /// Starts drawing on a worker thread with the solver at index `solver` of `solvers()`, so the window stays
+    /// responsive. The worker gets its own copy of the participants and constraints.
+    fn start(
+        ctx: &Context,
+        participants: Vec<Participant>,
+        exclusions: HashMap<Participant, HashSet<Participant>>,
+        households: Vec<Group<Participant>>,
+        solver: usize,
+    ) -> Self {
+        let (sender, result) = channel();
+        let cancel = CancelToken::default();
+        let progress = Arc::new(Mutex::new(None));
+
+        let (ctx, token, latest) = (ctx.clone(), cancel.clone(), progress.clone());
+        thread::spawn(move || {
+            let exclusions = exclusions.iter().map(|(giver, excluded)| (giver, excluded.iter().collect())).collect();
+            let constraints = Constraints::default().exclusions(exclusions).groups(households.iter());
+            let repaint = ctx.clone();
+            let mut control = SearchControl::default()
+                .cancel_token(token)
+                .record_trace(TRACE_LIMIT)
+                .on_progress(move |progress| {
+                    *latest.lock().unwrap() = Some(progress);
+                    repaint.request_repaint();
+                });
+
+            let result = generate_secret_santa_with_solver(
+                participants.iter(),
+                &constraints,
+                solvers()[solver].as_ref(),
+                &mut control,
+                &mut rand::thread_rng(),
+            );
+            let result = match result {
+                Ok(assignment) => Ok(assignment
+                    .into_iter()
+                    .sorted()
+                    .map(|(giver, recipient)| format!("{} gives a gift to {}", giver, recipient))
+                    .collect()),
+                Err(error) => Err(error.to_string()),
+            };
+            let finished = FinishedDraw {
+                result,
+                stats: control.stats().clone(),
+                trace: search_trace(participants.iter(), &control),
+            };
+
+            // Nobody listens anymore if a newer draw replaced this one
+            let _ = sender.send(finished);
+            ctx.request_repaint();
+        });
+
+        Self { result, cancel, progress }
+    }
+}
+
+/// Steps through the trace of a search, to see where it spent its time.
+#[derive(Default)]
+struct Replay {
+    trace: Trace,
+    /// How many events have been replayed.
+    step: usize,
+    /// Where traces are saved to and loaded from.
+    file: String,
+    /// Why the last save or load failed.
+    error: Option<String>,
+}
+
+impl Replay {
+    fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("File");
+            ui.text_edit_singleline(&mut self.file);
+            if ui.button("Save").clicked() {
+                let saved = File::create(&self.file).and_then(|file| self.trace.write_to(BufWriter::new(file)));
+                self.error = saved.err().map(|error| error.to_string());
+            }
+            if ui.button("Load").clicked() {
+                match File::open(&self.file).and_then(|file| Trace::read_from(BufReader::new(file))) {
+                    Ok(trace) => {
+                        *self = Replay { trace, step: 0, file: self.file.clone(), error: None };
+                    }
+                    Err(error) => self.error = Some(error.to_string()),
+                }
+            }
+        });
+        if let Some(error) = &self.error {
+            ui.colored_label(Color32::RED, error);
+        }
+
+        let events = self.trace.events.len();
+        ui.add(egui::Slider::new(&mut self.step, 0..=events).text(format!("of {} events", events)));
+        if self.trace.truncated {
+            ui.label("The search went on after the last recorded event.");
+        }
+
+        let name = |participant: usize| self.trace.participants[participant].as_str();
+        if let Some(&event) = self.step.checked_sub(1).and_then(|last| self.trace.events.get(last)) {
+            ui.label(match event {
+                TraceEvent::Choose { options } => format!("Choose among {} options", options),
+                TraceEvent::Push { participant, new_cycle: true } => format!("Start a new cycle with {}", name(participant)),
+                TraceEvent::Push { participant, new_cycle: false } => format!("Give to {}", name(participant)),
+                TraceEvent::Pop { participant } => format!("Backtrack from {}", name(participant)),
+            });
+        }
+        let path = self.trace.path_at(self.step);
+        let steps: Vec<String> = path
+            .iter()
+            .map(|&(participant, new_cycle)| match new_cycle {
+                true => format!("| {}", name(participant)),
+                false => format!("-> {}", name(participant)),
+            })
+            .collect();
+        ui.label(steps.join(" "));
+    }
+}
+
 // Modify the `SecretSanta` struct to wrap `participants` in `Rc<RefCell<...>>`.
 pub struct SecretSanta {
     searched_participant: String,
     participants: Vec<Participant>, // Shared and mutable
-    exclusions: HashMap<Participant, Participant>,
+    exclusions: HashMap<Participant, HashSet<Participant>>,
+    households: Vec<Group<Participant>>,
+    solvers: Vec<Box<dyn DrawSolver<Participant>>>,
+    solver: usize,
+    /// The last draw, one line per pair, or why it failed.
+    draw: Option<Result<Vec<String>, String>>,
+    /// What the search behind the last draw went through.
+    stats: Option<SearchStats>,
+    replay: Replay,
+    running: Option<RunningDraw>,
 }
 
 impl Default for SecretSanta {
@@ -67,13 +224,44 @@ impl Default for SecretSanta {
             searched_participant: String::default(),
             participants: generate_participants(50).iter().cloned().collect_vec(),
             exclusions: HashMap::default(),
+            households: Vec::default(),
+            solvers: solvers(),
+            solver: 0,
+            draw: None,
+            stats: None,
+            replay: Replay { file: "trace.txt".to_string(), ..Replay::default() },
+            running: None,
         }
     }
 }
 
+impl SecretSanta {
+    /// Selects the solver called `name`, keeping the current one if no solver has that name.
+    pub fn solver(mut self, name: &str) -> Self {
+        if let Some(index) = self.solvers.iter().position(|solver| solver.name() == name) {
+            self.solver = index;
+        }
+        self
+    }
+}
+
 impl eframe::App for SecretSanta {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
-        let Self { searched_participant, participants, exclusions } = self;
+        let Self { searched_participant, participants, exclusions, households, solvers, solver, draw, stats, replay, running } = self;
+
+        if let Some(current) = running {
+            match current.result.try_recv() {
+                Ok(finished) => {
+                    *draw = Some(finished.result);
+                    *stats = Some(finished.stats);
+                    replay.trace = finished.trace;
+                    replay.step = 0;
+                    *running = None;
+                }
+                Err(TryRecvError::Disconnected) => *running = None,
+                Err(TryRecvError::Empty) => {}
+            }
+        }
 
         // Clone Rc<RefCell<...>> to pass shared ownership to ListView
         //let participants_clone = participants.iter().cloned().map(|item| item);
@@ -101,6 +289,18 @@ impl eframe::App for SecretSanta {
                         ui.close_menu();
                     }
 
+                    if ui.add_enabled(selected.len() >= 2, egui::Button::new("Make Household")).clicked() {
+                        // Participants belong to a single household
+                        for household in households.iter_mut() {
+                            household.members.retain(|x| !selected.contains(x));
+                        }
+                        households.retain(|household| household.members.len() >= 2);
+
+                        let name = format!("Household {}", households.len() + 1);
+                        households.push(Group::new(name, selected.iter().map(|&x| x.clone())));
+                        ui.close_menu();
+                    }
+
                     if ui.button("Add Participant").clicked() {
                         let rng = RNG::try_from(&Language::Elven).unwrap();
 
@@ -124,8 +324,80 @@ impl eframe::App for SecretSanta {
             });
         });
 
+        // Forget removed participants everywhere else
+        exclusions.retain(|giver, _| participants.contains(giver));
+        for excluded in exclusions.values_mut() {
+            excluded.retain(|x| participants.contains(x));
+        }
+        for household in households.iter_mut() {
+            household.members.retain(|x| participants.contains(x));
+        }
+        households.retain(|household| household.members.len() >= 2);
+
         CentralPanel::default().show(ctx, |ui| {
             ui.heading("My egui Application");
+
+            for household in households.iter() {
+                let members = household.members.iter().sorted().join(", ");
+                ui.label(format!("{}: {}", household.name, members));
+            }
+
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Solver")
+                    .selected_text(solvers[*solver].name())
+                    .show_ui(ui, |ui| {
+                        for (index, option) in solvers.iter().enumerate() {
+                            ui.selectable_value(solver, index, option.name());
+                        }
+                    });
+
+                if ui.button("Draw").clicked() {
+                    // A new draw supersedes the running one
+                    if let Some(previous) = running.take() {
+                        previous.cancel.cancel();
+                    }
+                    *running = Some(RunningDraw::start(
+                        ctx,
+                        participants.clone(),
+                        exclusions.clone(),
+                        households.clone(),
+                        *solver,
+                    ));
+                }
+
+                if let Some(current) = running {
+                    ui.spinner();
+                    if let Some(progress) = *current.progress.lock().unwrap() {
+                        let placed = progress.depth as f32 / progress.max_depth.max(1) as f32;
+                        let text = format!("{} nodes, {} backtracks", progress.nodes, progress.backtracks);
+                        ui.add(egui::ProgressBar::new(placed).desired_width(200.0).text(text));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        current.cancel.cancel();
+                    }
+                }
+            });
+
+            if let Some(stats) = stats {
+                let phases = stats.phases.iter().map(|(phase, time)| format!("{} {:.1?}", phase, time)).join(", ");
+                ui.label(format!(
+                    "{} nodes, {} backtracks, deepest {} participants ({})",
+                    stats.nodes, stats.backtracks, stats.max_depth, phases
+                ));
+            }
+            egui::CollapsingHeader::new("Search trace").show(ui, |ui| replay.show(ui));
+
+            egui::ScrollArea::vertical().show(ui, |ui| match draw {
+                Some(Ok(pairs)) => {
+                    for pair in pairs.iter() {
+                        ui.label(pair);
+                    }
+                }
+                Some(Err(error)) => {
+                    ui.colored_label(Color32::RED, format!("No valid draw: {}", error));
+                }
+                None => {}
+            });
         });
     }
 }